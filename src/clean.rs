@@ -0,0 +1,121 @@
+//! Restoring a pristine working tree after a pull: removing untracked or
+//! ignored files, and checking that no tracked file was modified.
+
+use clap::ValueEnum;
+use git2::{Error, Pathspec, PathspecFlags, Repository, Status, StatusOptions};
+use std::fs;
+use std::path::PathBuf;
+
+use crate::sparse::{is_skip_worktree, remove_empty_parents};
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum CleanScope {
+    /// Files git does not track and does not ignore
+    Untracked,
+    /// Files matched by ignore rules
+    Ignored,
+    /// Both
+    All,
+}
+
+impl CleanScope {
+    fn removes(self, status: Status) -> bool {
+        match self {
+            CleanScope::Untracked => status.is_wt_new(),
+            CleanScope::Ignored => status.is_ignored(),
+            CleanScope::All => status.is_wt_new() || status.is_ignored(),
+        }
+    }
+}
+
+/// Removes the files in `scope`, except those matching one of the `keep`
+/// pathspecs. Nested repositories are never touched. Returns the removed
+/// paths.
+pub fn clean(repo: &Repository, scope: CleanScope, keep: &[String]) -> Result<Vec<PathBuf>, Error> {
+    let workdir = repo.workdir()
+        .ok_or_else(|| Error::from_str("Cleaning needs a working tree"))?
+        .to_path_buf();
+    let keep = Pathspec::new(keep.iter())?;
+
+    let mut options = StatusOptions::new();
+    options.include_untracked(true).recurse_untracked_dirs(true)
+        .include_ignored(scope != CleanScope::Untracked).recurse_ignored_dirs(true);
+    let mut removed = Vec::new();
+    for entry in repo.statuses(Some(&mut options))?.iter() {
+        let path = match entry.path() {
+            Some(path) if scope.removes(entry.status()) => PathBuf::from(path),
+            _ => continue,
+        };
+        // Directories only show up here when they hold another repository
+        if path.as_os_str().to_string_lossy().ends_with('/') || keep.matches_path(&path, PathspecFlags::DEFAULT) {
+            continue;
+        }
+        if fs::remove_file(workdir.join(&path)).is_ok() {
+            remove_empty_parents(&workdir, &path);
+            removed.push(path);
+        }
+    }
+    Ok(removed)
+}
+
+/// Tracked files whose working tree or index copy differs from HEAD.
+/// Files a sparse checkout marked skip-worktree don't count: libgit2
+/// reports them as deleted, git does not.
+pub fn modified_tracked_files(repo: &Repository) -> Result<Vec<PathBuf>, Error> {
+    let mut options = StatusOptions::new();
+    options.include_untracked(false).include_ignored(false);
+    let statuses = repo.statuses(Some(&mut options))?;
+    let index = repo.index()?;
+    Ok(statuses.iter()
+        .filter_map(|entry| entry.path().map(PathBuf::from))
+        .filter(|path| !is_skip_worktree(&index, path))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{commit_file, setup_repos, test_keys};
+    use crate::{fetch_commit, pull_repo, status};
+    use std::path::Path;
+
+    #[test]
+    fn test_clean() {
+        let repos = setup_repos("clean");
+        let local = Repository::open(&repos.local).unwrap();
+        fs::write(repos.local.join(".git/info/exclude"), "*.log\n").unwrap();
+        fs::create_dir_all(repos.local.join("tmp/cache")).unwrap();
+        fs::write(repos.local.join("tmp/cache/stray.txt"), "stray\n").unwrap();
+        fs::write(repos.local.join("debug.log"), "log\n").unwrap();
+        fs::write(repos.local.join("local.env"), "keep me\n").unwrap();
+
+        let removed = clean(&local, CleanScope::Untracked, &["local.env".to_string()]).unwrap();
+        assert_eq!(vec![PathBuf::from("tmp/cache/stray.txt")], removed);
+        assert!(!repos.local.join("tmp").exists());
+        assert!(repos.local.join("debug.log").exists());
+
+        let removed = clean(&local, CleanScope::All, &["local.env".to_string()]).unwrap();
+        assert_eq!(vec![PathBuf::from("debug.log")], removed);
+        assert!(repos.local.join("local.env").exists());
+        assert!(modified_tracked_files(&local).unwrap().is_empty());
+
+        fs::write(repos.local.join("README.md"), "edited\n").unwrap();
+        assert_eq!(vec![Path::new("README.md")], modified_tracked_files(&local).unwrap());
+    }
+
+    #[test]
+    fn test_sparse_checkout_is_not_dirty() {
+        let repos = setup_repos("clean-sparse");
+        let upstream = Repository::open(&repos.upstream).unwrap();
+        fs::create_dir_all(repos.upstream.join("service")).unwrap();
+        let latest_sha = commit_file(&upstream, "service/main.txt", "service\n", "Add service");
+        fetch_commit(&repos.local, &mut test_keys().fetch_options(), "origin", "master", latest_sha).unwrap();
+
+        pull_repo(&repos.local, latest_sha, &["service".to_string()]).unwrap();
+
+        let local = Repository::open(&repos.local).unwrap();
+        assert!(!repos.local.join("README.md").exists());
+        assert!(modified_tracked_files(&local).unwrap().is_empty());
+        assert!(status::is_clean(&local).unwrap());
+    }
+}
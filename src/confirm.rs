@@ -0,0 +1,83 @@
+//! `--pull-confirmation`: a y/N prompt before a pull, for manual syncs
+//! on a workstation.
+
+use clap::ValueEnum;
+use git2::{DiffStatsFormat, Error, Oid, Repository};
+use std::io::{self, BufRead, IsTerminal, Write};
+
+use crate::history::incoming_commits;
+
+/// What `--pull-confirmation` does when there is no terminal to ask.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum NonInteractive {
+    /// Pull as if confirmation was not asked for
+    Proceed,
+    /// Leave the change for a run that can ask
+    Skip,
+}
+
+/// How many incoming commits the prompt lists.
+const PROMPT_COMMITS: usize = 20;
+
+/// `git diff --stat` style summary of the changes from `from` to `to`.
+pub fn diff_stats(repo: &Repository, from: Oid, to: Oid) -> Result<String, Error> {
+    let old = repo.find_commit(from)?.tree()?;
+    let new = repo.find_commit(to)?.tree()?;
+    let diff = repo.diff_tree_to_tree(Some(&old), Some(&new), None)?;
+    let stats = diff.stats()?.to_buf(DiffStatsFormat::FULL, 80)?;
+    Ok(String::from_utf8_lossy(&stats).into_owned())
+}
+
+/// True when both stdin and stderr are terminals, so there is someone
+/// to prompt.
+pub fn is_interactive() -> bool {
+    io::stdin().is_terminal() && io::stderr().is_terminal()
+}
+
+/// Shows the incoming commits and diff stats on stderr and asks whether to
+/// pull them.
+pub fn ask(repo: &Repository, name: &str, from: Oid, to: Oid) -> anyhow::Result<bool> {
+    let mut stderr = io::stderr();
+    writeln!(stderr, "[{}] Incoming commits:", name)?;
+    for commit in incoming_commits(repo, from, to, PROMPT_COMMITS)? {
+        writeln!(stderr, "  {}", commit.to_human())?;
+    }
+    write!(stderr, "{}", diff_stats(repo, from, to)?)?;
+    write!(stderr, "Pull {} into {}? [y/N] ", to, name)?;
+    stderr.flush()?;
+
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    Ok(is_yes(&answer))
+}
+
+fn is_yes(answer: &str) -> bool {
+    matches!(answer.trim().to_ascii_lowercase().as_str(), "y" | "yes")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{commit_file, setup_repos};
+
+    #[test]
+    fn test_is_yes() {
+        assert!(is_yes("y\n"));
+        assert!(is_yes(" YES "));
+        assert!(!is_yes("\n"));
+        assert!(!is_yes("no"));
+    }
+
+    #[test]
+    fn test_diff_stats() {
+        let repos = setup_repos("diff-stats");
+        let upstream = Repository::open(&repos.upstream).unwrap();
+        let from = upstream.head().unwrap().target().unwrap();
+        let to = commit_file(&upstream, "notes.txt", "one\ntwo\n", "Add notes");
+
+        let stats = diff_stats(&upstream, from, to).unwrap();
+
+        assert!(stats.contains("notes.txt"));
+        assert!(stats.contains("1 file changed, 2 insertions(+)"));
+    }
+}
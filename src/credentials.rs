@@ -0,0 +1,118 @@
+//! SSH key authentication.
+
+use git2::{Cred, Error, FetchOptions, RemoteCallbacks};
+use std::cell::Cell;
+use std::path::{Path, PathBuf};
+
+/// The SSH keys to authenticate with, tried in order.
+#[derive(Clone)]
+pub struct SshKeys {
+    paths: Vec<PathBuf>,
+    /// Index of the key offered last on the current connection.
+    offered: Cell<Option<usize>>,
+    /// Index of the key the last successful connection authenticated with.
+    /// Later connections offer it first.
+    accepted: Cell<Option<usize>>,
+}
+
+impl SshKeys {
+    pub fn new(paths: Vec<PathBuf>) -> SshKeys {
+        SshKeys { paths, offered: Cell::new(None), accepted: Cell::new(None) }
+    }
+
+    /// Callbacks offering each key in turn. libgit2 calls the credentials
+    /// callback again after a key is rejected, so every call moves on to the
+    /// next key until they run out.
+    pub fn callbacks(&self) -> RemoteCallbacks<'_> {
+        self.offered.set(None);
+        let attempt = Cell::new(0);
+        let mut callbacks = RemoteCallbacks::new();
+        callbacks.credentials(move |_url, username_from_url, _allowed_types| {
+            let key = self.next_key(&attempt)?;
+            Cred::ssh_key(
+                username_from_url.unwrap_or("git"),
+                None,
+                key,
+                None,
+            )
+        });
+        callbacks
+    }
+
+    pub fn fetch_options(&self) -> FetchOptions<'_> {
+        let mut fetch_options = FetchOptions::new();
+        fetch_options.remote_callbacks(self.callbacks());
+        fetch_options
+    }
+
+    /// Records that the connection the latest callbacks were used for
+    /// succeeded, so the key offered last is the one that works.
+    pub fn accept(&self) {
+        if let Some(i) = self.offered.get() {
+            self.accepted.set(Some(i));
+        }
+    }
+
+    /// The key a connection was accepted with, if any was needed at all.
+    pub fn used(&self) -> Option<&Path> {
+        self.accepted.get().and_then(|i| self.paths.get(i)).map(PathBuf::as_path)
+    }
+
+    /// Takes over which key `other` (a clone used on another thread) ended
+    /// up authenticating with.
+    pub fn adopt_used(&self, other: &SshKeys) {
+        if other.accepted.get().is_some() {
+            self.accepted.set(other.accepted.get());
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.paths.len()
+    }
+
+    /// The index of the key to offer on `attempt`: the accepted key first,
+    /// then the others in order.
+    fn key_index(&self, attempt: usize) -> Option<usize> {
+        match self.accepted.get() {
+            Some(accepted) if attempt == 0 => Some(accepted),
+            Some(accepted) => (0..self.paths.len()).filter(|&i| i != accepted).nth(attempt - 1),
+            None => (attempt < self.paths.len()).then_some(attempt),
+        }
+    }
+
+    fn next_key(&self, attempt: &Cell<usize>) -> Result<&Path, Error> {
+        let i = self.key_index(attempt.get()).ok_or_else(|| Error::new(
+            git2::ErrorCode::Auth,
+            git2::ErrorClass::Ssh,
+            format!("All {} SSH keys were rejected", self.paths.len()),
+        ))?;
+        attempt.set(attempt.get() + 1);
+        self.offered.set(Some(i));
+        Ok(&self.paths[i])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keys_are_tried_in_turn() {
+        let keys = SshKeys::new(vec![PathBuf::from("first"), PathBuf::from("second"), PathBuf::from("third")]);
+        let attempt = Cell::new(0);
+
+        assert_eq!(Path::new("first"), keys.next_key(&attempt).unwrap());
+        assert_eq!(Path::new("second"), keys.next_key(&attempt).unwrap());
+        // Rejected or not, a key is only reported once the connection works
+        assert_eq!(None, keys.used());
+        assert_eq!(Path::new("third"), keys.next_key(&attempt).unwrap());
+        assert_eq!(git2::ErrorCode::Auth, keys.next_key(&attempt).unwrap_err().code());
+        keys.accept();
+        assert_eq!(Some(Path::new("third")), keys.used());
+
+        // The next connection starts with the key that worked
+        let attempt = Cell::new(0);
+        let order: Vec<_> = (0..3).map(|_| keys.next_key(&attempt).unwrap().to_path_buf()).collect();
+        assert_eq!(vec![PathBuf::from("third"), PathBuf::from("first"), PathBuf::from("second")], order);
+    }
+}
@@ -0,0 +1,114 @@
+//! Free disk space checks and byte sizes.
+
+use anyhow::{anyhow, bail, Result};
+use std::path::Path;
+
+/// Parses a number of bytes with an optional `K`, `M` or `G` suffix
+/// (powers of 1024), e.g. `500M`. A trailing `B` is accepted.
+pub fn parse_size(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let (digits, unit) = match s.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+        Some((i, _)) => s.split_at(i),
+        None => (s, ""),
+    };
+    let multiplier: u64 = match unit.to_ascii_uppercase().trim_end_matches('B') {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        _ => bail!("'{}' is not a size such as 500K or 2G", s),
+    };
+    digits.parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| anyhow!("'{}' is not a size such as 500K or 2G", s))
+}
+
+/// Formats bytes for humans, e.g. `1.5 GiB`.
+pub fn format_size(bytes: u64) -> String {
+    match bytes {
+        b if b >= 1 << 30 => format!("{:.1} GiB", b as f64 / (1u64 << 30) as f64),
+        b if b >= 1 << 20 => format!("{:.1} MiB", b as f64 / (1u64 << 20) as f64),
+        b => format!("{:.1} KiB", b as f64 / 1024.0),
+    }
+}
+
+/// Bytes available to unprivileged users on the file system holding `path`.
+#[cfg(unix)]
+pub fn free_space(path: &Path) -> Result<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    // SAFETY: statvfs only writes into the struct we hand it
+    let (result, stat) = unsafe {
+        let mut stat: libc::statvfs = std::mem::zeroed();
+        (libc::statvfs(c_path.as_ptr(), &mut stat), stat)
+    };
+    if result != 0 {
+        bail!("Cannot read free space of {}: {}", path.display(), std::io::Error::last_os_error());
+    }
+    // The field types differ between platforms
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(windows)]
+pub fn free_space(path: &Path) -> Result<u64> {
+    use std::os::windows::ffi::OsStrExt;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetDiskFreeSpaceExW(directory: *const u16, free_to_caller: *mut u64, total: *mut u64, total_free: *mut u64) -> i32;
+    }
+
+    let wide: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+    let mut available = 0u64;
+    // SAFETY: the path is NUL terminated, and the totals we don't need may be null
+    let result = unsafe { GetDiskFreeSpaceExW(wide.as_ptr(), &mut available, std::ptr::null_mut(), std::ptr::null_mut()) };
+    if result == 0 {
+        bail!("Cannot read free space of {}: {}", path.display(), std::io::Error::last_os_error());
+    }
+    Ok(available)
+}
+
+/// Without a way to read free space, `--min-free-space` fails every run
+/// rather than letting pulls through unchecked.
+#[cfg(not(any(unix, windows)))]
+pub fn free_space(path: &Path) -> Result<u64> {
+    bail!("Free space of {} cannot be checked on this platform, so --min-free-space cannot be used", path.display())
+}
+
+/// Fails unless at least `required` bytes are free under `path`.
+pub fn ensure_free_space(path: &Path, required: u64) -> Result<()> {
+    let available = free_space(path)?;
+    if available < required {
+        bail!("Only {} free on {}, but --min-free-space requires {}; not pulling",
+            format_size(available), path.display(), format_size(required));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(1500, parse_size("1500").unwrap());
+        assert_eq!(2 << 30, parse_size("2G").unwrap());
+        assert_eq!(512 << 20, parse_size("512MB").unwrap());
+        assert!(parse_size("lots").is_err());
+    }
+
+    #[cfg(any(unix, windows))]
+    #[test]
+    fn test_ensure_free_space() {
+        let dir = std::env::temp_dir();
+
+        assert!(free_space(&dir).unwrap() > 0);
+        assert!(ensure_free_space(&dir, 0).is_ok());
+        let err = ensure_free_space(&dir, u64::MAX).unwrap_err();
+        assert!(err.to_string().contains("requires"));
+    }
+}
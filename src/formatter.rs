@@ -0,0 +1,256 @@
+//! Rendering of `RunOutcome`s, one implementation per `--output` format.
+
+use crate::history::CommitSummary;
+use crate::hooks::HookRun;
+use crate::remotes::RemoteFetch;
+use crate::outcome::{DeferReason, NoChangeReason, Outcome, PullMethod, RunOutcome, SkipReason};
+use crate::output::{json_opt, json_string, OutputFormat};
+
+pub trait OutcomeFormatter {
+    fn format(&self, run: &RunOutcome) -> String;
+}
+
+pub struct HumanFormatter;
+
+pub struct JsonFormatter;
+
+impl OutputFormat {
+    pub fn formatter(self) -> Box<dyn OutcomeFormatter> {
+        match self {
+            OutputFormat::Human => Box::new(HumanFormatter),
+            OutputFormat::Json => Box::new(JsonFormatter),
+        }
+    }
+}
+
+impl OutcomeFormatter for HumanFormatter {
+    fn format(&self, run: &RunOutcome) -> String {
+        let mut out = outcome_line(run);
+        for fetch in &run.remote_fetches {
+            out.push_str("\n  ");
+            out.push_str(&fetch.to_human());
+        }
+        for hook in &run.hooks {
+            out.push_str("\n  ");
+            out.push_str(&hook.to_human());
+        }
+        for hook in &run.planned_hooks {
+            out.push_str(&format!("\n  would run the {} hook", hook));
+        }
+        for guard in &run.planned_guards {
+            out.push_str(&format!("\n  would stop at {}", guard));
+        }
+        out
+    }
+}
+
+fn outcome_line(run: &RunOutcome) -> String {
+    let repo = &run.repo;
+    let outcome = match (&run.outcome, &run.error) {
+        (_, Some(error)) => return format!("[{}] {}", repo, error.message),
+        (Some(outcome), None) => outcome,
+        (None, None) => return format!("[{}] No outcome", repo),
+    };
+    match outcome {
+        Outcome::UpToDate { sha } => format!("[{}] Up to date ({})", repo, sha),
+        Outcome::NoChange { sha, remote_sha, reason: NoChangeReason::AlreadyContained } => {
+            format!("[{}] Nothing to pull: HEAD ({}) already contains {}", repo, sha, remote_sha)
+        }
+        Outcome::NoChange { sha, remote_sha, reason: NoChangeReason::SubtreeUnchanged } => {
+            format!("[{}] Nothing to pull: no change under the subtree prefix in {}, HEAD stays at {}", repo, remote_sha, sha)
+        }
+        Outcome::Deferred { sha, until, reason: DeferReason::DeployWindow } => {
+            format!("[{}] Change detected ({}), deferred until {}", repo, sha, until)
+        }
+        Outcome::Deferred { sha, until, reason: DeferReason::CommitAge } => {
+            format!("[{}] Change detected ({}), deferred until {} because the commit is too recent", repo, sha, until)
+        }
+        Outcome::Skipped { sha, reason: SkipReason::Declined } => {
+            format!("[{}] Change detected ({}), not pulled: declined", repo, sha)
+        }
+        Outcome::Skipped { sha, reason: SkipReason::NotInteractive } => {
+            format!("[{}] Change detected ({}), not pulled: no terminal to confirm on", repo, sha)
+        }
+        Outcome::LocalAhead { sha, ahead } => {
+            format!("[{}] Local is ahead of {} by {} commits; not fast-forwardable", repo, sha, ahead)
+        }
+        Outcome::WouldPull { from, to, commits, method } => {
+            let how = match method {
+                PullMethod::FastForward => "fast-forward",
+                PullMethod::Merge => "merge",
+                PullMethod::Checkout => "detached checkout",
+            };
+            format!("[{}] Would pull {}..{} ({} commits, {})", repo, from, to, commits, how)
+        }
+        Outcome::Pulled { from, to, commits } => {
+            let mut out = match from {
+                Some(from) => format!("[{}] Pulled {}..{}", repo, from, to),
+                None => format!("[{}] Pulled {}", repo, to),
+            };
+            for commit in commits {
+                out.push_str("\n  ");
+                out.push_str(&commit.to_human());
+            }
+            out
+        }
+        Outcome::MergePending { from, to, commits } => {
+            let mut out = format!("[{}] Merged {} into the working tree; HEAD ({}) has diverged, commit the merge to finish", repo, to, from);
+            for commit in commits {
+                out.push_str("\n  ");
+                out.push_str(&commit.to_human());
+            }
+            out
+        }
+    }
+}
+
+impl OutcomeFormatter for JsonFormatter {
+    fn format(&self, run: &RunOutcome) -> String {
+        let mut fields = vec![
+            format!("\"repo\":{}", json_string(&run.repo)),
+            format!("\"outcome\":{}", json_string(run.outcome.as_ref().map_or("error", Outcome::kind))),
+        ];
+        match &run.outcome {
+            None => {}
+            Some(Outcome::UpToDate { sha }) => fields.push(format!("\"sha\":\"{}\"", sha)),
+            Some(Outcome::NoChange { sha, reason, .. }) => {
+                fields.push(format!("\"sha\":\"{}\"", sha));
+                fields.push(format!("\"reason\":{}", json_string(reason.kind())));
+            }
+            Some(Outcome::Deferred { sha, until, reason }) => {
+                fields.push(format!("\"sha\":\"{}\"", sha));
+                fields.push(format!("\"deferred_until\":{}", json_string(until)));
+                fields.push(format!("\"reason\":{}", json_string(reason.kind())));
+            }
+            Some(Outcome::Skipped { sha, reason }) => {
+                fields.push(format!("\"sha\":\"{}\"", sha));
+                fields.push(format!("\"reason\":{}", json_string(reason.kind())));
+            }
+            Some(Outcome::LocalAhead { sha, ahead }) => {
+                fields.push(format!("\"sha\":\"{}\"", sha));
+                fields.push(format!("\"ahead\":{}", ahead));
+            }
+            Some(Outcome::WouldPull { from, to, commits, method }) => {
+                fields.push(format!("\"from\":\"{}\"", from));
+                fields.push(format!("\"to\":\"{}\"", to));
+                fields.push(format!("\"commit_count\":{}", commits));
+                fields.push(format!("\"fast_forward\":{}", *method == PullMethod::FastForward));
+                fields.push(format!("\"method\":{}", json_string(method.kind())));
+            }
+            Some(Outcome::MergePending { from, to, commits }) => {
+                fields.push(format!("\"from\":\"{}\"", from));
+                fields.push(format!("\"to\":\"{}\"", to));
+                let commits: Vec<String> = commits.iter().map(CommitSummary::to_json).collect();
+                fields.push(format!("\"commits\":[{}]", commits.join(",")));
+            }
+            Some(Outcome::Pulled { from, to, commits }) => {
+                fields.push(format!("\"from\":{}", from.map(|sha| format!("\"{}\"", sha)).unwrap_or_else(|| "null".to_string())));
+                fields.push(format!("\"to\":\"{}\"", to));
+                let commits: Vec<String> = commits.iter().map(CommitSummary::to_json).collect();
+                fields.push(format!("\"commits\":[{}]", commits.join(",")));
+            }
+        }
+        let sha = |sha: Option<git2::Oid>| json_opt(sha.map(|sha| sha.to_string()).as_deref());
+        fields.push(format!("\"old_sha\":{}", sha(run.old_sha)));
+        fields.push(format!("\"new_sha\":{}", sha(run.new_sha)));
+        fields.push(format!("\"remote_sha\":{}", sha(run.remote_sha)));
+        fields.push(format!("\"behind\":{}", run.behind.map_or("null".to_string(), |n| n.to_string())));
+        // A plan has to come out the same for the same repository state
+        if run.dry_run {
+            fields.push("\"dry_run\":true".to_string());
+            let hooks: Vec<String> = run.planned_hooks.iter().map(|hook| json_string(hook)).collect();
+            fields.push(format!("\"planned_hooks\":[{}]", hooks.join(",")));
+            let guards: Vec<String> = run.planned_guards.iter().map(|guard| json_string(guard)).collect();
+            fields.push(format!("\"planned_guards\":[{}]", guards.join(",")));
+        } else {
+            fields.push(format!("\"duration_ms\":{}", run.duration.as_millis()));
+        }
+        if run.offline {
+            fields.push("\"offline\":true".to_string());
+        }
+        if !run.remote_fetches.is_empty() {
+            let fetches: Vec<String> = run.remote_fetches.iter().map(RemoteFetch::to_json).collect();
+            fields.push(format!("\"remotes\":[{}]", fetches.join(",")));
+        }
+        let hooks: Vec<String> = run.hooks.iter().map(HookRun::to_json).collect();
+        fields.push(format!("\"hooks\":[{}]", hooks.join(",")));
+        if let Some(error) = &run.error {
+            let git = error.git.map_or("null".to_string(), |git| format!(
+                "{{\"code\":{},\"class\":{},\"raw_code\":{},\"raw_class\":{}}}",
+                json_string(&format!("{:?}", git.code)),
+                json_string(&format!("{:?}", git.class)),
+                git.raw_code,
+                git.raw_class,
+            ));
+            fields.push(format!("\"error\":{{\"category\":{},\"message\":{},\"git\":{}}}",
+                json_string(error.category), json_string(&error.message), git));
+        }
+        format!("{{{}}}", fields.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::outcome::RunError;
+    use git2::Oid;
+
+    fn run(outcome: Outcome) -> RunOutcome {
+        RunOutcome { outcome: Some(outcome), ..RunOutcome::new("api") }
+    }
+
+    #[test]
+    fn test_json_formatter() {
+        let sha = Oid::from_str("449022de3b3ebcfbbbb010f2ca91f724df03b33e").unwrap();
+
+        assert_eq!(
+            concat!(r#"{"repo":"api","outcome":"up_to_date","sha":"449022de3b3ebcfbbbb010f2ca91f724df03b33e","#,
+                r#""old_sha":null,"new_sha":null,"remote_sha":null,"behind":null,"duration_ms":0,"hooks":[]}"#),
+            JsonFormatter.format(&run(Outcome::UpToDate { sha })),
+        );
+        assert!(JsonFormatter.format(&run(Outcome::Pulled { from: None, to: sha, commits: Vec::new() })).contains(r#""from":null"#));
+
+        let failed = RunOutcome {
+            error: Some(RunError::new("network", "connection refused".to_string())),
+            ..RunOutcome::new("api")
+        };
+        assert!(JsonFormatter.format(&failed).contains(
+            r#""outcome":"error","#));
+        assert!(JsonFormatter.format(&failed).ends_with(
+            r#""error":{"category":"network","message":"connection refused","git":null}}"#));
+
+        let auth = anyhow::Error::new(git2::Error::new(git2::ErrorCode::Auth, git2::ErrorClass::Ssh, "denied"))
+            .context("Failed to fetch");
+        let failed = RunOutcome { error: Some(RunError::from_error(&auth)), ..RunOutcome::new("api") };
+        assert!(JsonFormatter.format(&failed).contains(
+            r#""git":{"code":"Auth","class":"Ssh","raw_code":-16,"raw_class":23}"#));
+    }
+
+    #[test]
+    fn test_json_plan() {
+        let from = Oid::from_str("449022de3b3ebcfbbbb010f2ca91f724df03b33e").unwrap();
+        let to = Oid::from_str("b6fc4c620b67d95f953a5c1c1230aaab5db5a1b0").unwrap();
+        let plan = RunOutcome {
+            dry_run: true,
+            duration: std::time::Duration::from_millis(42),
+            ..run(Outcome::WouldPull { from, to, commits: 3, method: PullMethod::Merge })
+        };
+
+        let json = JsonFormatter.format(&plan);
+        assert!(json.contains(r#""outcome":"would_pull","#));
+        assert!(json.contains(r#""commit_count":3,"fast_forward":false,"method":"merge","#));
+        assert!(json.contains(r#""dry_run":true,"planned_hooks":[],"planned_guards":[]"#));
+        assert!(!json.contains("duration_ms"));
+    }
+
+    #[test]
+    fn test_human_formatter() {
+        let sha = Oid::from_str("449022de3b3ebcfbbbb010f2ca91f724df03b33e").unwrap();
+        let deferred = run(Outcome::Deferred { sha, until: "02:00".to_string(), reason: DeferReason::DeployWindow });
+
+        assert_eq!(
+            "[api] Change detected (449022de3b3ebcfbbbb010f2ca91f724df03b33e), deferred until 02:00",
+            OutputFormat::Human.formatter().format(&deferred),
+        );
+    }
+}
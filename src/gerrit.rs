@@ -0,0 +1,152 @@
+//! Watching a Gerrit change instead of a branch.
+//!
+//! Gerrit publishes patchset `P` of change `C` as `refs/changes/NN/C/P`,
+//! where `NN` is the last two digits of `C`. A new patchset is usually a
+//! rewrite of the previous one, so it is checked out on a detached HEAD
+//! rather than merged into a branch.
+
+use anyhow::{anyhow, Error as AnyError};
+use git2::build::CheckoutBuilder;
+use git2::{Direction, Error, ErrorClass, ErrorCode, Oid, Repository};
+use std::fmt;
+use std::str::FromStr;
+
+use crate::credentials::SshKeys;
+use crate::status::REFLOG_PREFIX;
+use crate::trace;
+
+/// `--gerrit-change`: a change number, optionally pinned to a patchset.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChangeRef {
+    pub change: u32,
+    pub patchset: Option<u32>,
+}
+
+impl ChangeRef {
+    /// The ref prefix every patchset of the change lives under.
+    fn prefix(&self) -> String {
+        format!("refs/changes/{:02}/{}/", self.change % 100, self.change)
+    }
+
+    pub fn patchset_ref(&self, patchset: u32) -> String {
+        format!("{}{}", self.prefix(), patchset)
+    }
+
+    /// The pinned patchset, or else the highest one, among `refs`.
+    fn select(&self, refs: &[(String, Oid)]) -> Option<(u32, Oid)> {
+        let prefix = self.prefix();
+        refs.iter()
+            .filter_map(|(name, oid)| Some((name.strip_prefix(&prefix)?.parse::<u32>().ok()?, *oid)))
+            .filter(|(patchset, _)| self.patchset.is_none_or(|pinned| pinned == *patchset))
+            .max_by_key(|(patchset, _)| *patchset)
+    }
+}
+
+impl FromStr for ChangeRef {
+    type Err = AnyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || anyhow!("expected CHANGE or CHANGE/PATCHSET, got '{}'", s);
+        let (change, patchset) = match s.split_once('/') {
+            Some((change, patchset)) => (change, Some(patchset.parse().map_err(|_| invalid())?)),
+            None => (s, None),
+        };
+        Ok(ChangeRef { change: change.parse().map_err(|_| invalid())?, patchset })
+    }
+}
+
+impl fmt::Display for ChangeRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.patchset {
+            Some(patchset) => write!(f, "{}/{}", self.change, patchset),
+            None => write!(f, "{}", self.change),
+        }
+    }
+}
+
+/// A patchset as advertised by the remote.
+pub struct Patchset {
+    pub number: u32,
+    pub sha: Oid,
+    /// The patchset whose commit HEAD is at, if any.
+    pub checked_out: Option<u32>,
+}
+
+/// Reads the wanted patchset of `change` from the remote's ref
+/// advertisement, without fetching it.
+pub fn advertised_patchset(repo: &Repository, ssh_keys: &SshKeys, remote: &str, change: ChangeRef) -> Result<Patchset, Error> {
+    let mut remote = repo.find_remote(remote)?;
+    let connection = remote.connect_auth(Direction::Fetch, Some(ssh_keys.callbacks()), None)?;
+    ssh_keys.accept();
+    let refs: Vec<(String, Oid)> = connection.list()?
+        .iter()
+        .map(|head| (head.name().to_string(), head.oid()))
+        .collect();
+
+    let (number, sha) = change.select(&refs).ok_or_else(|| Error::new(
+        ErrorCode::NotFound,
+        ErrorClass::Reference,
+        format!("Gerrit change {} is not advertised by the remote", change),
+    ))?;
+    trace::event("advertised_refs", &[
+        ("count", refs.len().to_string()),
+        ("change", trace::text(change.to_string())),
+        ("patchset", number.to_string()),
+        ("sha", trace::text(sha.to_string())),
+    ]);
+    let head = repo.head().ok().and_then(|head| head.target());
+    let all = ChangeRef { patchset: None, ..change };
+    let checked_out = refs.iter()
+        .filter(|(_, oid)| Some(*oid) == head)
+        .find_map(|(name, _)| name.strip_prefix(&all.prefix())?.parse().ok());
+    Ok(Patchset { number, sha, checked_out })
+}
+
+/// Fetches patchset `number` of `change`; it only ends up in FETCH_HEAD.
+pub fn fetch_patchset(repo: &Repository, ssh_keys: &SshKeys, remote: &str, change: ChangeRef, number: u32) -> Result<(), Error> {
+    let mut remote = repo.find_remote(remote)?;
+    let refspec = change.patchset_ref(number);
+    remote.fetch(&[&refspec], Some(&mut ssh_keys.fetch_options()), None)?;
+    ssh_keys.accept();
+    crate::trace_fetch(&remote, &refspec);
+    Ok(())
+}
+
+/// Checks out `sha` and detaches HEAD there, leaving every branch alone.
+pub fn checkout_detached(repo: &Repository, sha: Oid, patchset: u32) -> Result<(), Error> {
+    let commit = repo.find_commit(sha)?;
+    repo.checkout_tree(commit.as_object(), Some(CheckoutBuilder::new().safe()))?;
+    trace::event("checkout", &[("tree", trace::text(commit.tree_id().to_string())), ("detached", "true".to_string())]);
+    // Written as a direct HEAD so the reflog entry is one `status` finds
+    repo.reference("HEAD", sha, true, &format!("{} gerrit patchset {}", REFLOG_PREFIX, patchset))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_change_ref() {
+        let change: ChangeRef = "12345".parse().unwrap();
+        assert_eq!("refs/changes/45/12345/7", change.patchset_ref(7));
+        assert_eq!(ChangeRef { change: 7, patchset: Some(2) }, "7/2".parse().unwrap());
+        assert_eq!("refs/changes/07/7/2", ChangeRef { change: 7, patchset: None }.patchset_ref(2));
+        assert!("12345/latest".parse::<ChangeRef>().is_err());
+    }
+
+    #[test]
+    fn test_select_patchset() {
+        let (one, two) = (Oid::from_bytes(&[1; 20]).unwrap(), Oid::from_bytes(&[2; 20]).unwrap());
+        let refs = vec![
+            ("refs/changes/45/12345/1".to_string(), one),
+            ("refs/changes/45/12345/10".to_string(), two),
+            ("refs/changes/45/12345/meta".to_string(), one),
+            ("refs/changes/45/99945/11".to_string(), one),
+        ];
+
+        assert_eq!(Some((10, two)), "12345".parse::<ChangeRef>().unwrap().select(&refs));
+        assert_eq!(Some((1, one)), "12345/1".parse::<ChangeRef>().unwrap().select(&refs));
+        assert_eq!(None, "12345/3".parse::<ChangeRef>().unwrap().select(&refs));
+    }
+}
@@ -0,0 +1,86 @@
+//! Summaries of the upstream commits and files a pull brings in.
+
+use git2::{Delta, Error, Oid, Repository, Sort};
+use std::path::PathBuf;
+
+use crate::output::{format_timestamp, json_string};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CommitSummary {
+    pub sha: Oid,
+    pub author: String,
+    /// Commit time in seconds since the epoch.
+    pub time: i64,
+    pub subject: String,
+}
+
+impl CommitSummary {
+    pub fn to_human(&self) -> String {
+        format!("{:.10} {} {} {}", self.sha.to_string(), format_timestamp(self.time), self.author, self.subject)
+    }
+
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"sha\":\"{}\",\"author\":{},\"time\":{},\"subject\":{}}}",
+            self.sha,
+            json_string(&self.author),
+            self.time,
+            json_string(&self.subject),
+        )
+    }
+}
+
+/// Up to `limit` commits reachable from `to` but not from `from`, newest first.
+pub fn incoming_commits(repo: &Repository, from: Oid, to: Oid, limit: usize) -> Result<Vec<CommitSummary>, Error> {
+    let mut walk = repo.revwalk()?;
+    walk.set_sorting(Sort::TOPOLOGICAL | Sort::TIME)?;
+    walk.push(to)?;
+    walk.hide(from)?;
+
+    walk.take(limit)
+        .map(|oid| {
+            let commit = repo.find_commit(oid?)?;
+            let author = commit.author().name().unwrap_or("unknown").to_string();
+            Ok(CommitSummary {
+                sha: commit.id(),
+                author,
+                time: commit.time().seconds(),
+                subject: commit.summary().unwrap_or("").to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Files added or modified between the commits `from` and `to`.
+pub fn changed_files(repo: &Repository, from: Oid, to: Oid) -> Result<Vec<PathBuf>, Error> {
+    let old_tree = repo.find_commit(from)?.tree()?;
+    let new_tree = repo.find_commit(to)?.tree()?;
+    let diff = repo.diff_tree_to_tree(Some(&old_tree), Some(&new_tree), None)?;
+
+    Ok(diff.deltas()
+        .filter(|delta| !matches!(delta.status(), Delta::Deleted))
+        .filter_map(|delta| delta.new_file().path().map(PathBuf::from))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{commit_file, setup_repos};
+
+    #[test]
+    fn test_incoming_commits_are_limited() {
+        let repos = setup_repos("history");
+        let repo = Repository::open(&repos.upstream).unwrap();
+        let from = repo.head().unwrap().target().unwrap();
+        commit_file(&repo, "a.txt", "a\n", "First upstream");
+        commit_file(&repo, "b.txt", "b\n", "Second upstream");
+        let to = commit_file(&repo, "c.txt", "c\n", "Third upstream");
+
+        let commits = incoming_commits(&repo, from, to, 2).unwrap();
+
+        let subjects: Vec<&str> = commits.iter().map(|c| c.subject.as_str()).collect();
+        assert_eq!(vec!["Third upstream", "Second upstream"], subjects);
+        assert_eq!("Test", commits[0].author);
+    }
+}
@@ -0,0 +1,224 @@
+//! User supplied commands run at points in the watcher's lifecycle.
+
+use anyhow::{anyhow, Context, Result};
+use git2::{ErrorClass, ErrorCode, Oid};
+use std::path::Path;
+use std::process::{Command, ExitStatus, Stdio};
+use std::time::{Duration, Instant};
+
+use crate::output::json_string;
+
+/// What happened when a hook ran, for the end-of-run summary.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HookRun {
+    pub name: &'static str,
+    /// `None` when the hook could not be started or was killed by a signal.
+    pub exit_code: Option<i32>,
+    pub duration: Duration,
+}
+
+impl HookRun {
+    pub fn succeeded(&self) -> bool {
+        self.exit_code == Some(0)
+    }
+
+    pub fn to_human(&self) -> String {
+        let status = match self.exit_code {
+            Some(code) => format!("exited with {}", code),
+            None => "did not finish".to_string(),
+        };
+        format!("{} hook {} ({} ms)", self.name, status, self.duration.as_millis())
+    }
+
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"name\":{},\"exit_code\":{},\"duration_ms\":{}}}",
+            json_string(self.name),
+            self.exit_code.map_or("null".to_string(), |code| code.to_string()),
+            self.duration.as_millis(),
+        )
+    }
+}
+
+/// Sorts a failure into a coarse category an alerting hook can act on.
+pub fn error_category(err: &anyhow::Error) -> &'static str {
+    let git_err = match err.chain().find_map(|cause| cause.downcast_ref::<git2::Error>()) {
+        Some(git_err) => git_err,
+        None => return "other",
+    };
+
+    match (git_err.code(), git_err.class()) {
+        (ErrorCode::Auth, _) | (ErrorCode::Certificate, _) => "auth",
+        (ErrorCode::Conflict, _) | (ErrorCode::MergeConflict, _) | (_, ErrorClass::Merge) | (_, ErrorClass::Checkout) => "conflict",
+        (_, ErrorClass::Net) | (_, ErrorClass::Ssh) | (_, ErrorClass::Http) | (_, ErrorClass::Ssl) => "network",
+        (_, ErrorClass::Odb) | (_, ErrorClass::Zlib) | (_, ErrorClass::Indexer) => "corruption",
+        _ => "other",
+    }
+}
+
+/// Runs `command` through the platform shell with the extra environment.
+pub fn run_command(command: &str, envs: &[(&str, String)]) -> Result<ExitStatus> {
+    shell_command(command, envs).status().map_err(|e| anyhow!("Failed to start '{}': {}", command, e))
+}
+
+/// Runs the `--detect-command` and reads the upstream commit it prints.
+/// Anything but a single full SHA on stdout, or a non-zero exit, is an error.
+pub fn detect_sha(command: &str, envs: &[(&str, String)]) -> Result<Oid> {
+    let started = Instant::now();
+    let output = shell_command(command, envs)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|e| anyhow!("Failed to start '{}': {}", command, e))?;
+    crate::trace::event("detect_command", &[
+        ("command", json_string(command)),
+        ("exit_code", output.status.code().map_or("null".to_string(), |code| code.to_string())),
+        ("stdout", json_string(&String::from_utf8_lossy(&output.stdout))),
+        ("duration_ms", started.elapsed().as_millis().to_string()),
+    ]);
+    if !output.status.success() {
+        return Err(anyhow!("'{}' exited with {}", command, output.status));
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let sha = stdout.trim();
+    let hex = sha.chars().all(|c| c.is_ascii_hexdigit());
+    if hex && sha.len() == 64 {
+        return Err(anyhow!("'{}' printed the SHA-256 id {}; SHA-256 repositories are not supported", command, sha));
+    }
+    // Oid::from_str would pad an abbreviation
+    if !hex || sha.len() != 40 {
+        return Err(anyhow!("'{}' printed {:?}, expected a full commit SHA (40 hex characters)", command, sha));
+    }
+    Oid::from_str(sha).with_context(|| format!("'{}' printed {}, which is not a valid object id", command, sha))
+}
+
+fn shell_command(command: &str, envs: &[(&str, String)]) -> Command {
+    let mut shell = if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    shell.arg(command);
+    for (key, value) in envs {
+        shell.env(key, value);
+    }
+    shell
+}
+
+/// Runs `command` as the hook called `name`. A failing hook is reported on
+/// stderr and in the returned summary, never as an error.
+pub fn run_hook(name: &'static str, command: &str, repo_name: &str, envs: &[(&str, String)]) -> HookRun {
+    let started = Instant::now();
+    let exit_code = match run_command(command, envs) {
+        Ok(status) if status.success() => status.code(),
+        Ok(status) => {
+            eprintln!("[{}] {} hook exited with {}", repo_name, name, status);
+            status.code()
+        }
+        Err(hook_err) => {
+            eprintln!("[{}] {} hook failed: {:#}", repo_name, name, hook_err);
+            None
+        }
+    };
+    let hook = HookRun { name, exit_code, duration: started.elapsed() };
+    crate::trace::event("hook", &[
+        ("name", json_string(name)),
+        ("command", json_string(command)),
+        ("exit_code", exit_code.map_or("null".to_string(), |code| code.to_string())),
+        ("duration_ms", hook.duration.as_millis().to_string()),
+    ]);
+    hook
+}
+
+/// Runs the `--on-error` hook for `err`. Problems with the hook are only
+/// reported so they never replace the error that triggered it.
+pub fn on_error(command: &str, repo_name: &str, err: &anyhow::Error) -> HookRun {
+    let envs = [
+        ("REPO_WATCHER_REPO_NAME", repo_name.to_string()),
+        ("REPO_WATCHER_ERROR_CATEGORY", error_category(err).to_string()),
+        ("REPO_WATCHER_ERROR_MESSAGE", format!("{:#}", err)),
+    ];
+    run_hook("on-error", command, repo_name, &envs)
+}
+
+/// Runs the `--on-no-change` heartbeat hook with the SHA HEAD is still at.
+pub fn on_no_change(command: &str, repo_name: &str, sha: git2::Oid) -> HookRun {
+    let envs = [
+        ("REPO_WATCHER_REPO_NAME", repo_name.to_string()),
+        ("REPO_WATCHER_SHA", sha.to_string()),
+    ];
+    run_hook("on-no-change", command, repo_name, &envs)
+}
+
+/// Runs the `--checkout-helper` that moves HEAD of `local_path` from
+/// `from` to `to` on the watcher's behalf.
+pub fn checkout_helper(command: &str, repo_name: &str, local_path: &Path, from: git2::Oid, to: git2::Oid) -> HookRun {
+    let envs = [
+        ("REPO_WATCHER_REPO_NAME", repo_name.to_string()),
+        ("REPO_WATCHER_LOCAL_PATH", local_path.display().to_string()),
+        ("REPO_WATCHER_FROM_SHA", from.to_string()),
+        ("REPO_WATCHER_SHA", to.to_string()),
+    ];
+    run_hook("checkout-helper", command, repo_name, &envs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn git_error(code: ErrorCode, class: ErrorClass) -> anyhow::Error {
+        anyhow::Error::new(git2::Error::new(code, class, "test")).context("Failed to fetch")
+    }
+
+    #[test]
+    fn test_error_category() {
+        assert_eq!("auth", error_category(&git_error(ErrorCode::Auth, ErrorClass::Ssh)));
+        assert_eq!("network", error_category(&git_error(ErrorCode::GenericError, ErrorClass::Net)));
+        assert_eq!("conflict", error_category(&git_error(ErrorCode::MergeConflict, ErrorClass::Merge)));
+        assert_eq!("other", error_category(&anyhow!("something else")));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_on_error_receives_env() {
+        let out = std::env::temp_dir().join(format!("repo-watcher-on-error-{}", std::process::id()));
+        let command = format!("printf '%s' \"$REPO_WATCHER_ERROR_CATEGORY\" > '{}'", out.display());
+
+        let hook = on_error(&command, "test", &git_error(ErrorCode::Auth, ErrorClass::Ssh));
+
+        assert!(hook.succeeded());
+        assert_eq!("auth", std::fs::read_to_string(&out).unwrap());
+        std::fs::remove_file(&out).ok();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_hook_reports_failure() {
+        let hook = run_hook("on-error", "exit 3", "test", &[]);
+
+        assert!(!hook.succeeded());
+        assert_eq!(Some(3), hook.exit_code);
+        assert!(hook.to_json().starts_with(r#"{"name":"on-error","exit_code":3,"#));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_detect_sha() {
+        let sha = "3258f627da61e823143d9db425508da9153711a9";
+        assert_eq!(Oid::from_str(sha).unwrap(), detect_sha(&format!("echo ' {}'", sha), &[]).unwrap());
+
+        let err = detect_sha("echo v1.2.3", &[]).unwrap_err();
+        assert!(err.to_string().contains("expected a full commit SHA"));
+        let err = detect_sha(&format!("echo {}", &sha[..12]), &[]).unwrap_err();
+        assert!(err.to_string().contains("expected a full commit SHA"));
+        let err = detect_sha(&format!("echo {}", "ab".repeat(32)), &[]).unwrap_err();
+        assert!(err.to_string().contains("SHA-256 repositories are not supported"));
+        let err = detect_sha(&format!("echo {}; exit 2", sha), &[]).unwrap_err();
+        assert!(err.to_string().contains("exited with"));
+    }
+}
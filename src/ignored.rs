@@ -0,0 +1,47 @@
+//! Warns about incoming tracked files that local ignore rules also match.
+//!
+//! This usually means generated files were committed upstream and will
+//! overwrite the copies generated on the deploy box.
+
+use git2::{Error, Oid, Repository};
+use std::path::PathBuf;
+
+use crate::history::changed_files;
+
+/// Paths the pull from `from` to `to` would write that `.gitignore`,
+/// `info/exclude` or `core.excludesFile` would otherwise ignore.
+pub fn incoming_ignored_paths(repo: &Repository, from: Oid, to: Oid) -> Result<Vec<PathBuf>, Error> {
+    let mut ignored = Vec::new();
+    for path in changed_files(repo, from, to)? {
+        if repo.status_should_ignore(&path)? {
+            ignored.push(path);
+        }
+    }
+    Ok(ignored)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fetch_commit;
+    use crate::tests::{commit_file, setup_repos, test_keys};
+    use std::fs;
+
+    #[test]
+    fn test_reports_incoming_ignored_paths() {
+        let repos = setup_repos("ignored");
+        let local = Repository::open(&repos.local).unwrap();
+        fs::write(local.path().join("info/exclude"), "dist/\n").unwrap();
+        let from = local.head().unwrap().target().unwrap();
+
+        let upstream = Repository::open(&repos.upstream).unwrap();
+        fs::create_dir_all(repos.upstream.join("dist")).unwrap();
+        commit_file(&upstream, "dist/app.js", "built\n", "Commit build output");
+        let to = commit_file(&upstream, "src.txt", "source\n", "Change source");
+        fetch_commit(&repos.local, &mut test_keys().fetch_options(), "origin", "master", to).unwrap();
+
+        let ignored = incoming_ignored_paths(&local, from, to).unwrap();
+
+        assert_eq!(vec![PathBuf::from("dist/app.js")], ignored);
+    }
+}
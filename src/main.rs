@@ -1,190 +1,1618 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
-use git2::{Repository, RemoteCallbacks, Cred, MergeOptions, FetchOptions, Error};
-use anyhow::{Context, Result, anyhow};
+use git2::{Repository, RepositoryState, MergeOptions, Error, FetchOptions, Oid, Direction, ErrorCode, ErrorClass};
+use git2::build::CheckoutBuilder;
+use anyhow::{anyhow, bail, Context, Result};
 use std::path::{Path, PathBuf};
-use std::process;
-use dotenv::dotenv;
 use std::env;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+mod clean;
+mod confirm;
+mod credentials;
+mod disk;
+mod formatter;
+mod gerrit;
+mod history;
+mod hooks;
+mod ignored;
+mod integrity;
+mod maintenance;
+mod merge;
+mod mtimes;
+mod outcome;
+mod output;
+mod permissions;
+mod probe;
+mod recovery;
+mod remotes;
+mod selftest;
+mod sparse;
+mod status;
+mod throttle;
+mod trace;
+mod watcher;
+mod window;
+mod worktrees;
+
+use clean::CleanScope;
+use confirm::NonInteractive;
+use credentials::SshKeys;
+use gerrit::ChangeRef;
+use outcome::{DeferReason, ExitCodeRule, NoChangeReason, Outcome, PullMethod, RunError, RunOutcome, SkipReason};
+use permissions::Owner;
+use throttle::Throttle;
+use output::OutputFormat;
+use watcher::{Applied, Watcher};
+use window::DeployWindow;
 
 /// Monitors a GitHub repository for changes and pulls them
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Cli {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
     /// Local repository path
     #[clap(short, long, value_parser)]
     local_path: Option<PathBuf>,
-    
+
     /// GitHub repository URL to monitor
     #[clap(short, long)]
     remote: Option<String>,
-    
+
     /// Branch to monitor
     #[clap(short, long)]
     branch: Option<String>,
 
-    /// Path to the SSH key for authentication
+    /// Path to the SSH key for authentication. Can be given more than once;
+    /// the keys are tried in order until one is accepted
     #[clap(short, long, value_parser)]
-    ssh_key_path: Option<PathBuf>,
+    ssh_key_path: Vec<PathBuf>,
 
     /// Path to the .env file
     #[clap(short, long, value_parser)]
-    env_file: Option<PathBuf>,        
+    env_file: Option<PathBuf>,
+
+    /// Label used for this repository in output and hooks.
+    /// Defaults to the repository directory name
+    #[clap(long)]
+    repo_name: Option<String>,
+
+    /// Re-clone the repository if its object database is corrupted.
+    /// The corrupted copy is kept next to the fresh clone.
+    #[clap(long)]
+    auto_reclone_on_corruption: bool,
+
+    /// Only pull inside this local time window, e.g. 02:00-04:00.
+    /// Can be given more than once; changes outside are reported and deferred
+    #[clap(long, value_name = "HH:MM-HH:MM")]
+    defer_until: Vec<DeployWindow>,
+
+    /// Command to run when the check or pull fails. It receives
+    /// REPO_WATCHER_ERROR_CATEGORY and REPO_WATCHER_ERROR_MESSAGE
+    #[clap(long, value_name = "COMMAND")]
+    on_error: Option<String>,
+
+    /// Command to run when a poll finds nothing new, e.g. to ping a
+    /// dead man's switch. It receives the current SHA as REPO_WATCHER_SHA
+    #[clap(long, value_name = "COMMAND")]
+    on_no_change: Option<String>,
+
+    /// Detect changes with a full fetch and FETCH_HEAD instead of reading
+    /// the remote's ref advertisement first
+    #[clap(long)]
+    full_fetch: bool,
+
+    /// After a successful pull, atomically write the new HEAD SHA to this file
+    #[clap(long, value_name = "PATH")]
+    write_sha_file: Option<PathBuf>,
+
+    /// Write the SHA file on every run, not only when something was pulled
+    #[clap(long, requires = "write_sha_file")]
+    always_write_sha: bool,
+
+    /// Only materialize paths matching this pattern after a pull.
+    /// Can be given more than once
+    #[clap(long, value_name = "PATTERN")]
+    sparse_path: Vec<String>,
+
+    /// When pulling, list up to this many of the incoming commits
+    #[clap(long, value_name = "N")]
+    history_depth_report: Option<usize>,
+
+    /// Octal mode to apply to files written by a pull, e.g. 0640.
+    /// Directories are left alone
+    #[clap(long, value_name = "MODE", value_parser = permissions::parse_mode, visible_alias = "checkout-dir-permissions")]
+    checkout_file_mode: Option<u32>,
+
+    /// Owner to give files written by a pull; only applied when running as root
+    #[clap(long, value_name = "UID:GID")]
+    checkout_owner: Option<Owner>,
+
+    /// Give up if connecting to the remote takes longer than this many
+    /// seconds, both to read its refs and to fetch. Transfers are not limited
+    #[clap(long, value_name = "SECONDS", conflicts_with = "full_fetch")]
+    connect_timeout: Option<u64>,
+
+    /// Compare HEAD with the remote-tracking ref and pull from it without
+    /// any network access, for use with a separate fetcher
+    #[clap(long, conflicts_with_all = ["full_fetch", "fetch_all_remotes", "connect_timeout", "max_bandwidth"])]
+    compare_with_remote_tracking: bool,
+
+    /// After a pull, check that every object of the new tree can be read
+    #[clap(long)]
+    post_merge_fsck: bool,
+
+    /// After a pull, remove untracked files, ignored files or both
+    #[clap(long, value_enum, value_name = "SCOPE")]
+    clean_after_pull: Option<CleanScope>,
+
+    /// Pathspec of files `--clean-after-pull` must keep. Can be repeated
+    #[clap(long, value_name = "PATTERN", requires = "clean_after_pull")]
+    clean_exclude: Vec<String>,
+
+    /// Fail the run if tracked files differ from the new HEAD after a pull
+    #[clap(long)]
+    fail_on_dirty_after: bool,
+
+    /// After a pull, refresh the commit-graph file to speed up history
+    /// walks in repositories with deep history
+    #[clap(long)]
+    commit_graph: bool,
+
+    /// Only pull a remote tip once its commit time is at least this many
+    /// seconds in the past
+    #[clap(long, value_name = "SECONDS")]
+    min_commit_age: Option<u64>,
+
+    /// Track only this directory of the remote branch: it is the only path
+    /// checked out, and commits that change nothing under it are not pulled
+    #[clap(long, value_name = "PATH")]
+    subtree_prefix: Option<String>,
+
+    /// Keep the average fetch rate under this many bytes per second.
+    /// Accepts K, M and G suffixes, e.g. 512K
+    #[clap(long, value_name = "RATE", value_parser = throttle::parse_rate)]
+    max_bandwidth: Option<u64>,
+
+    /// Refuse to pull when less than this much disk space is free at the
+    /// repository, e.g. 2G
+    #[clap(long, value_name = "SIZE", value_parser = disk::parse_size)]
+    min_free_space: Option<u64>,
+
+    /// Decide what to do without changing HEAD, the index or the working
+    /// tree, and run no hooks. New objects are still fetched
+    #[clap(long)]
+    dry_run: bool,
+
+    /// Fetch every configured remote before checking the watched one, so
+    /// all remote-tracking refs stay current. Only the watched remote is pulled
+    #[clap(long)]
+    fetch_all_remotes: bool,
+
+    /// Stop with a `local_ahead` outcome instead of merging when the local
+    /// branch has commits the remote does not
+    #[clap(long)]
+    warn_local_ahead: bool,
+
+    /// Take the remote SHA from the stdout of this command instead of the
+    /// remote's refs, then fetch and pull with git as usual
+    #[clap(long, value_name = "COMMAND",
+        conflicts_with_all = ["compare_with_remote_tracking", "full_fetch", "connect_timeout", "gerrit_change"])]
+    detect_command: Option<String>,
+
+    /// After a pull, give files whose content did not change their old
+    /// modification times back, so mtime based builds skip them
+    #[clap(long)]
+    preserve_mtimes: bool,
+
+    /// Fail the run when a hook fails. By default failed hooks are only
+    /// listed in the summary
+    #[clap(long)]
+    fail_on_hook_error: bool,
+
+    /// Follow a Gerrit change (CHANGE, or CHANGE/PATCHSET to pin one)
+    /// instead of the branch, checking its newest patchset out on a detached HEAD
+    #[clap(long, value_name = "CHANGE[/PATCHSET]",
+        conflicts_with_all = ["sparse_path", "subtree_prefix", "compare_with_remote_tracking", "full_fetch",
+            "connect_timeout", "max_bandwidth", "fetch_all_remotes", "min_commit_age", "warn_local_ahead",
+            "pull_confirmation", "checkout_helper"])]
+    gerrit_change: Option<ChangeRef>,
+
+    /// Show the incoming commits and diff stats and ask before pulling.
+    /// Only asks when attached to a terminal, see --non-interactive
+    #[clap(long)]
+    pull_confirmation: bool,
+
+    /// Answer yes to --pull-confirmation, for scripts
+    #[clap(short, long, requires = "pull_confirmation")]
+    yes: bool,
+
+    /// What --pull-confirmation does without a terminal to ask on
+    #[clap(long, value_enum, default_value = "proceed", value_name = "ACTION")]
+    non_interactive: NonInteractive,
+
+    /// Leave the checkout to this command, e.g. one run through sudo as a
+    /// more privileged user. It gets the target as REPO_WATCHER_SHA
+    #[clap(long, value_name = "COMMAND", conflicts_with_all = ["sparse_path", "subtree_prefix"])]
+    checkout_helper: Option<String>,
+
+    /// Fetch and pull as usual but run no hooks, e.g. during a change
+    /// freeze. Can also be set with DISABLE_HOOKS=1
+    #[clap(long)]
+    disable_hooks: bool,
+
+    /// Exit with CODE (0-255) for an outcome such as pulled or error_auth.
+    /// Can be repeated; also read from EXIT_CODES=pulled=10,error=2
+    #[clap(long, value_name = "OUTCOME=CODE")]
+    exit_code: Vec<ExitCodeRule>,
+
+    /// Append a timestamped JSON lines trace of every step to this file,
+    /// for bug reports
+    #[clap(long, value_name = "PATH")]
+    trace_file: Option<PathBuf>,
+
+    /// Output format
+    #[clap(long, value_enum, default_value = "human", global = true)]
+    output: OutputFormat,
 }
 
-fn fetch_latest_commit_sha(local_path: &Path, ssh_key_path: &Path, remote: &str, branch: &str) -> Result<String, Error> {
-    let repo = Repository::open(local_path)?;
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Connect to the remote, time the ref advertisement and exit without
+    /// fetching anything
+    Probe,
+    /// Print local and remote SHAs, ahead/behind counts and cleanliness
+    /// without pulling, then exit
+    Status,
+    /// Run a full detect and pull cycle against temporary local
+    /// repositories and report each step
+    SelfTest,
+}
 
-    let mut callbacks = RemoteCallbacks::new();
-    callbacks.credentials(|_url, username_from_url, _allowed_types| {
-        Cred::ssh_key(
-            username_from_url.unwrap(),
-            None,
-            ssh_key_path,
-            None,
-        )
-    });
+/// Connection settings taken from the command line, falling back to the
+/// environment (and the .env file).
+struct Settings {
+    name: String,
+    /// The repository path with symlinks resolved, used for every file
+    /// system operation.
+    local_path: PathBuf,
+    /// The path as the user gave it, for messages.
+    display_path: PathBuf,
+    remote: String,
+    branch: String,
+    ssh_keys: SshKeys,
+    disable_hooks: bool,
+}
+
+impl Settings {
+    fn from_args(args: &Cli) -> Result<Settings> {
+        // Load configurations from .env file if provided
+        if let Some(env_path) = &args.env_file {
+            dotenv::from_path(env_path).ok();
+        }
+
+        // Override with command-line arguments or use .env values
+        let display_path = args.local_path.clone().unwrap_or_else(|| PathBuf::from(env::var("LOCAL_PATH").expect("Local path not set")));
+        let local_path = std::fs::canonicalize(&display_path).unwrap_or_else(|_| display_path.clone());
+        let name = args.repo_name.clone()
+            .or_else(|| env::var("REPO_NAME").ok())
+            .unwrap_or_else(|| default_repo_name(&display_path));
+        let branch = args.branch.clone().unwrap_or_else(|| env::var("BRANCH").expect("Branch not set"));
+        let remotes: Vec<String> = Repository::open(&local_path)
+            .and_then(|repo| repo.remotes().map(|names| names.iter().flatten().map(String::from).collect()))
+            .unwrap_or_default();
+        let (branch_remote, branch) = normalize_branch(&branch, &remotes)?;
+        let remote = match (branch_remote, &args.remote) {
+            (Some(named), Some(remote)) if &named != remote => {
+                bail!("--branch names remote '{}' but --remote is '{}'", named, remote)
+            }
+            (Some(named), _) => named,
+            (None, Some(remote)) => remote.clone(),
+            (None, None) => env::var("REMOTE").expect("Remote not set"),
+        };
+
+        Ok(Settings {
+            name,
+            local_path,
+            display_path,
+            remote,
+            branch,
+            ssh_keys: SshKeys::new(if args.ssh_key_path.is_empty() {
+                env::split_paths(&env::var_os("SSH_KEY_PATH").expect("SSH key path not set")).collect()
+            } else {
+                args.ssh_key_path.clone()
+            }),
+            disable_hooks: args.disable_hooks || env::var("DISABLE_HOOKS").is_ok_and(|v| is_truthy(&v)),
+        })
+    }
+}
+
+/// Accepts `main`, `refs/heads/main`, `refs/remotes/origin/main` and
+/// `origin/main` when `origin` is one of `remotes`. Returns the remote the
+/// argument names, if any, and the bare branch name.
+fn normalize_branch(branch: &str, remotes: &[String]) -> Result<(Option<String>, String)> {
+    let (remote, name) = if let Some(name) = branch.strip_prefix("refs/heads/") {
+        (None, name)
+    } else if let Some(rest) = branch.strip_prefix("refs/remotes/") {
+        let (remote, name) = rest.split_once('/')
+            .ok_or_else(|| anyhow!("Branch '{}' names a remote but no branch", branch))?;
+        (Some(remote), name)
+    } else if branch.starts_with("refs/") {
+        bail!("Branch '{}' is not under refs/heads/", branch)
+    } else {
+        match branch.split_once('/') {
+            Some((remote, name)) if remotes.iter().any(|r| r == remote) => (Some(remote), name),
+            _ => (None, branch),
+        }
+    };
+    if !git2::Reference::is_valid_name(&format!("refs/heads/{}", name)) {
+        bail!("Branch '{}' is not a valid branch name", branch);
+    }
+    Ok((remote.map(String::from), name.to_string()))
+}
 
-    let mut fetch_options = FetchOptions::new();
-    fetch_options.remote_callbacks(callbacks);
+fn is_truthy(value: &str) -> bool {
+    !matches!(value.trim().to_ascii_lowercase().as_str(), "" | "0" | "false" | "no" | "off")
+}
 
-    repo.find_remote(remote)?
-        .fetch(&[branch], Some(&mut fetch_options), None)?;
+/// The last component of the repository path, ignoring a trailing `.git`.
+fn default_repo_name(local_path: &Path) -> String {
+    let path = if local_path.file_name().is_some_and(|n| n == ".git") {
+        local_path.parent().unwrap_or(local_path)
+    } else {
+        local_path
+    };
+    path.file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_else(|| local_path.display().to_string())
+}
+
+/// The refspec to fetch `branch` with, and whether the remote's configured
+/// fetch refspecs already cover it.
+///
+/// When they do, fetching just the branch lets libgit2 update the
+/// remote-tracking ref as usual. When they don't (e.g. a mirror configured
+/// for a few branches only) the tracking ref is named explicitly for this
+/// one fetch; the stored configuration is never changed.
+fn branch_refspec(remote: &git2::Remote, branch: &str) -> (String, bool) {
+    let source = format!("refs/heads/{}", branch);
+    let covered = remote.refspecs()
+        .any(|spec| spec.direction() == Direction::Fetch && spec.src_matches(&source));
+    if covered {
+        (source, true)
+    } else {
+        let name = remote.name().unwrap_or("origin");
+        (format!("+{}:refs/remotes/{}/{}", source, name, branch), false)
+    }
+}
+
+/// Fetches the branch and reads the new tip back from FETCH_HEAD.
+///
+/// Returns the object id rather than its hex string so callers never depend
+/// on the length of the hash, which differs between SHA-1 and SHA-256 repos.
+fn fetch_latest_commit_sha(local_path: &Path, fetch_options: &mut FetchOptions, remote: &str, branch: &str) -> Result<Oid, Error> {
+    let repo = Repository::open(local_path)?;
+
+    let mut remote = repo.find_remote(remote)?;
+    let (refspec, _) = branch_refspec(&remote, branch);
+    remote.fetch(&[&refspec], Some(fetch_options), None)?;
+    trace_fetch(&remote, &refspec);
 
     let fetch_head = repo.find_reference("FETCH_HEAD")?;
     let fetch_commit = repo.reference_to_annotated_commit(&fetch_head)?;
 
-    Ok(fetch_commit.id().to_string())
+    Ok(fetch_commit.id())
+}
+
+fn trace_fetch(remote: &git2::Remote, refspec: &str) {
+    let stats = remote.stats();
+    trace::event("fetch", &[
+        ("remote", trace::text(remote.name().unwrap_or_default())),
+        ("refspec", trace::text(refspec)),
+        ("received_bytes", stats.received_bytes().to_string()),
+        ("received_objects", stats.received_objects().to_string()),
+        ("indexed_objects", stats.indexed_objects().to_string()),
+    ]);
+}
+
+/// Reads the branch tip from the remote's ref advertisement without
+/// transferring any objects.
+fn advertised_commit_sha(local_path: &Path, ssh_keys: &SshKeys, remote: &str, branch: &str) -> Result<Oid, Error> {
+    let repo = Repository::open(local_path)?;
+    let mut remote = repo.find_remote(remote)?;
+    let connection = remote.connect_auth(Direction::Fetch, Some(ssh_keys.callbacks()), None)?;
+    ssh_keys.accept();
+
+    let refname = format!("refs/heads/{}", branch);
+    let heads = connection.list()?;
+    let sha = heads.iter()
+        .find(|head| head.name() == refname)
+        .map(|head| head.oid());
+    trace::event("advertised_refs", &[
+        ("count", heads.len().to_string()),
+        ("branch", trace::text(&refname)),
+        ("sha", sha.map_or("null".to_string(), |sha| trace::text(sha.to_string()))),
+    ]);
+    sha.ok_or_else(|| Error::new(
+        ErrorCode::NotFound,
+        ErrorClass::Reference,
+        format!("Branch '{}' is not advertised by the remote", branch),
+    ))
+}
+
+/// `advertised_commit_sha` on its own thread, so that a dead host fails
+/// after `timeout` instead of whenever the OS gives up on the connection.
+/// libgit2 has no connect timeout of its own.
+fn advertised_commit_sha_within(local_path: &Path, ssh_keys: &SshKeys, remote: &str, branch: &str, timeout: Duration) -> Result<Oid, Error> {
+    let (tx, rx) = mpsc::channel();
+    let (path, keys, remote_name, branch_name) = (local_path.to_path_buf(), ssh_keys.clone(), remote.to_string(), branch.to_string());
+    thread::spawn(move || {
+        let result = advertised_commit_sha(&path, &keys, &remote_name, &branch_name);
+        tx.send((result, keys)).ok();
+    });
+
+    let started = Instant::now();
+    let received = rx.recv_timeout(timeout);
+    trace::event("connect", &[
+        ("phase", trace::text("advertisement")),
+        ("timeout_ms", timeout.as_millis().to_string()),
+        ("ms", started.elapsed().as_millis().to_string()),
+        ("timed_out", received.is_err().to_string()),
+    ]);
+    match received {
+        Ok((result, keys)) => {
+            ssh_keys.adopt_used(&keys);
+            result
+        }
+        Err(_) => Err(Error::new(
+            ErrorCode::GenericError,
+            ErrorClass::Net,
+            format!("Timed out after {:?} connecting to remote '{}'", timeout, remote),
+        )),
+    }
+}
+
+/// `fetch_commit` on its own thread, failing after `timeout` unless the
+/// remote has started sending objects by then. As for the advertisement,
+/// only connecting and negotiating are limited, not the transfer.
+fn fetch_commit_within(local_path: &Path, ssh_keys: &SshKeys, throttle: Option<&Throttle>, remote: &str, branch: &str, sha: Oid, timeout: Duration) -> Result<()> {
+    enum Fetching {
+        Started,
+        Done(Result<()>, SshKeys, Option<Throttle>),
+    }
+    let (tx, rx) = mpsc::channel();
+    let (path, keys, throttle_copy) = (local_path.to_path_buf(), ssh_keys.clone(), throttle.cloned());
+    let (remote_name, branch_name) = (remote.to_string(), branch.to_string());
+    thread::spawn(move || {
+        let result = {
+            let mut callbacks = keys.callbacks();
+            let (mut started, pacing) = (Some(tx.clone()), throttle_copy.as_ref());
+            callbacks.transfer_progress(move |progress| {
+                if let Some(started) = started.take() {
+                    started.send(Fetching::Started).ok();
+                }
+                if let Some(throttle) = pacing {
+                    throttle.pace(progress.received_bytes());
+                }
+                true
+            });
+            let mut fetch_options = FetchOptions::new();
+            fetch_options.remote_callbacks(callbacks);
+            fetch_commit(&path, &mut fetch_options, &remote_name, &branch_name, sha)
+        };
+        if result.is_ok() {
+            keys.accept();
+        }
+        tx.send(Fetching::Done(result, keys, throttle_copy)).ok();
+    });
+
+    let started = Instant::now();
+    let first = rx.recv_timeout(timeout);
+    trace::event("connect", &[
+        ("phase", trace::text("fetch")),
+        ("timeout_ms", timeout.as_millis().to_string()),
+        ("ms", started.elapsed().as_millis().to_string()),
+        ("timed_out", first.is_err().to_string()),
+    ]);
+    let done = match first {
+        Ok(Fetching::Started) => rx.recv().ok(),
+        Ok(done) => Some(done),
+        Err(_) => return Err(Error::new(
+            ErrorCode::GenericError,
+            ErrorClass::Net,
+            format!("Timed out after {:?} connecting to remote '{}' to fetch", timeout, remote),
+        ).into()),
+    };
+    match done {
+        Some(Fetching::Done(result, keys, throttle_copy)) => {
+            ssh_keys.adopt_used(&keys);
+            if let (Some(throttle), Some(copy)) = (throttle, &throttle_copy) {
+                throttle.adopt(copy);
+            }
+            result
+        }
+        _ => bail!("The fetch from remote '{}' stopped without a result", remote),
+    }
+}
+
+/// Reads the tip of `refs/remotes/<remote>/<branch>`, as left by whatever
+/// else fetches this repository.
+fn tracking_commit_sha(local_path: &Path, remote: &str, branch: &str) -> Result<Oid, Error> {
+    let repo = Repository::open(local_path)?;
+    let refname = format!("refs/remotes/{}/{}", remote, branch);
+    match repo.refname_to_id(&refname) {
+        Err(e) if e.code() == ErrorCode::NotFound => Err(Error::new(
+            ErrorCode::NotFound,
+            ErrorClass::Reference,
+            format!("{} does not exist; nothing has fetched it yet", refname),
+        )),
+        result => result,
+    }
+}
+
+/// Downloads the objects for `sha`, once detection has shown it is new.
+/// Fetches the branch, which must bring in `sha`. If it doesn't, the branch
+/// moved after `sha` was read (or `sha` was never on it), which is reported
+/// as such instead of as a missing object.
+fn fetch_commit(local_path: &Path, fetch_options: &mut FetchOptions, remote_name: &str, branch: &str, sha: Oid) -> Result<()> {
+    let repo = Repository::open(local_path)?;
+
+    let mut remote = repo.find_remote(remote_name)?;
+    let (refspec, _) = branch_refspec(&remote, branch);
+    remote.fetch(&[&refspec], Some(fetch_options), None)?;
+    trace_fetch(&remote, &refspec);
+
+    if repo.find_commit(sha).is_err() {
+        bail!("{} is not on {}/{} after fetching; the branch moved during the fetch or never contained it",
+            sha, remote_name, branch);
+    }
+    Ok(())
 }
 
-fn check_for_new_commits(repo_path: &Path, latest_sha: &str) -> Result<bool, Error> {
+fn check_for_new_commits(repo_path: &Path, latest_sha: Oid) -> Result<bool, Error> {
     let repo = Repository::open(repo_path)?;
     let head = repo.head()?.peel_to_commit()?;
-    let local_sha = head.id().to_string();
 
-    Ok(local_sha != latest_sha)
+    Ok(head.id() != latest_sha)
 }
 
-fn pull_repo(local_path: &Path, remote: &str, branch: &str) -> Result<(), Error> {
+/// Brings in `latest_sha`, which must already be fetched. Fast-forwards
+/// when possible. Otherwise the merge is checked out into the index and
+/// working tree and left for someone to commit, as `repo.merge` does.
+///
+/// The merge is built in memory first, so a conflict leaves the working
+/// tree untouched instead of half merged. A merge of `latest_sha` that is
+/// still pending is reported again as is; any other merge in progress
+/// blocks the pull until it is committed or aborted.
+fn pull_repo(local_path: &Path, latest_sha: Oid, sparse_paths: &[String]) -> Result<Applied, Error> {
     let repo = Repository::open(local_path)?;
-    let mut remote = repo.find_remote(remote)?;
+    let merge_commit = repo.find_annotated_commit(latest_sha)?;
 
-    remote.fetch(&[branch], None, None)?;
+    let (analysis, _) = repo.merge_analysis(&[&merge_commit])?;
+    trace::event("merge_analysis", &[
+        ("sha", trace::text(latest_sha.to_string())),
+        ("up_to_date", analysis.is_up_to_date().to_string()),
+        ("fast_forward", analysis.is_fast_forward().to_string()),
+        ("normal", analysis.is_normal().to_string()),
+    ]);
+    if analysis.is_up_to_date() {
+        return Ok(Applied::AlreadyContained);
+    }
+    if repo.state() == RepositoryState::Merge {
+        let pending = std::fs::read_to_string(repo.path().join("MERGE_HEAD")).unwrap_or_default();
+        if pending.trim() == latest_sha.to_string() {
+            return Ok(Applied::MergePending);
+        }
+        return Err(Error::new(
+            ErrorCode::Unmerged,
+            ErrorClass::Merge,
+            format!("A merge is in progress; commit or abort it before {} can be pulled", latest_sha),
+        ));
+    }
 
-    let fetch_head = repo.find_reference("FETCH_HEAD").unwrap();
-    let merge_commit = repo.reference_to_annotated_commit(&fetch_head).unwrap();
+    let theirs = repo.find_commit(latest_sha)?;
+    if analysis.is_fast_forward() {
+        checkout(&repo, &theirs.tree()?, sparse_paths)?;
+        status::record_pull(&repo, latest_sha, "fast-forward")?;
+        return Ok(Applied::FastForward);
+    }
 
-    let mut merge_options = MergeOptions::new();
-    merge_options.fail_on_conflict(true);
-    repo.merge(&[&merge_commit], Some(&mut merge_options), None).unwrap();
-    Ok(())
+    let head = repo.head()?.peel_to_commit()?;
+    let mut index = repo.merge_commits(&head, &theirs, Some(&MergeOptions::new()))?;
+    merge::resolve_ours(&repo, &mut index, &head, &theirs)?;
+    if index.has_conflicts() {
+        return Err(Error::new(ErrorCode::MergeConflict, ErrorClass::Merge, "Merge has conflicts"));
+    }
+
+    let tree = repo.find_tree(index.write_tree_to(&repo)?)?;
+    checkout(&repo, &tree, sparse_paths)?;
+    // The merge state `git commit` picks up
+    let write = |file: &str, contents: String| std::fs::write(repo.path().join(file), contents)
+        .map_err(|e| Error::from_str(&format!("Failed to write {}: {}", file, e)));
+    write("MERGE_HEAD", format!("{}\n", latest_sha))?;
+    write("MERGE_MSG", format!("Merge {} into HEAD\n", latest_sha))?;
+    Ok(Applied::MergePending)
 }
 
+fn checkout(repo: &Repository, tree: &git2::Tree, sparse_paths: &[String]) -> Result<(), Error> {
+    if sparse_paths.is_empty() {
+        let updated = std::cell::Cell::new(0);
+        let mut builder = CheckoutBuilder::new();
+        builder.safe().progress(|_, completed, _| updated.set(completed));
+        repo.checkout_tree(tree.as_object(), Some(&mut builder))?;
+        trace::event("checkout", &[("tree", trace::text(tree.id().to_string())), ("files", updated.get().to_string())]);
+        return Ok(());
+    }
+    let removed = sparse::checkout(repo, tree, sparse_paths)?;
+    trace::event("checkout", &[("tree", trace::text(tree.id().to_string())), ("sparse", "true".to_string()), ("removed", removed.to_string())]);
+    Ok(())
+}
 
 fn main() -> Result<()> {
     let args = Cli::parse();
     // validate_args(&args)?;
-        
-    run(&args)?;
+
+    match args.command {
+        Some(Command::Probe) => {
+            let report = probe::probe(&Settings::from_args(&args)?).context("Probe failed")?;
+            println!("{}", report.render(args.output));
+        }
+        Some(Command::SelfTest) => {
+            let report = selftest::self_test();
+            println!("{}", report.render(args.output));
+            if !report.passed() {
+                std::process::exit(1);
+            }
+        }
+        Some(Command::Status) => {
+            let status = status::status(&Settings::from_args(&args)?).context("Status check failed")?;
+            println!("{}", status::render(&[status], args.output));
+        }
+        None => {
+            if let Some(path) = &args.trace_file {
+                trace::open(path).with_context(|| format!("Failed to open trace file {}", path.display()))?;
+            }
+            let settings = Settings::from_args(&args)?;
+            let rules = exit_code_rules(&args)?;
+            let report = run(&args, &settings);
+            let rendered = args.output.formatter().format(&report);
+            match (&report.error, args.output) {
+                (Some(_), OutputFormat::Human) => eprintln!("{}", rendered),
+                _ => println!("{}", rendered),
+            }
+            let code = report.exit_code(&rules);
+            if code != 0 {
+                std::process::exit(code);
+            }
+        }
+    }
     Ok(())
 }
 
-fn run(args: &Cli) -> Result<(), Error> {
-    // Load configurations from .env file if provided
-    if let Some(env_path) = args.env_file {
-        dotenv::from_path(env_path).ok();
-    }
-
-    // Override with command-line arguments or use .env values
-    let local_path = args.local_path.unwrap_or_else(|| PathBuf::from(env::var("LOCAL_PATH").expect("Local path not set")));
-    let remote = args.remote.unwrap_or_else(|| env::var("REMOTE").expect("Remote not set"));
-    let branch = args.branch.unwrap_or_else(|| env::var("BRANCH").expect("Branch not set"));
-    let ssh_key_path = args.ssh_key_path.unwrap_or_else(|| PathBuf::from(env::var("SSH_KEY_PATH").expect("SSH key path not set")));
-    
-    // let path_buf = PathBuf::from(local_path);
-    let path = local_path.as_path();
-    let ssh_key_path = ssh_key_path.as_path();
-    
-    let latest_sha = fetch_latest_commit_sha(&path, &ssh_key_path, &remote, &branch)
-        .context("Failed to fetch the latest commit SHA")?;    
-    
-    let has_new_commits = check_for_new_commits(&path, &latest_sha)
+/// `--exit-code` rules, or else the comma separated EXIT_CODES variable.
+/// Call after `Settings::from_args`, which loads the .env file.
+fn exit_code_rules(args: &Cli) -> Result<Vec<ExitCodeRule>> {
+    if !args.exit_code.is_empty() {
+        return Ok(args.exit_code.clone());
+    }
+    match env::var("EXIT_CODES") {
+        Ok(rules) => rules.split(',')
+            .filter(|rule| !rule.trim().is_empty())
+            .map(|rule| rule.parse().context("Invalid EXIT_CODES"))
+            .collect(),
+        Err(_) => Ok(Vec::new()),
+    }
+}
+
+/// Runs one watch cycle. Failures are recorded in the returned outcome
+/// rather than returned, so callers always get the full report.
+fn run(args: &Cli, settings: &Settings) -> RunOutcome {
+    trace::event("start", &[
+        ("repo", trace::text(&settings.name)),
+        ("local_path", trace::text(settings.local_path.to_string_lossy())),
+        ("remote", trace::text(&settings.remote)),
+        ("branch", trace::text(&settings.branch)),
+    ]);
+    let started = Instant::now();
+    let mut report = RunOutcome::new(&settings.name);
+    report.old_sha = head_sha(&settings.local_path).ok();
+
+    if settings.disable_hooks {
+        eprintln!("!!! [{}] Hooks are disabled for this run", settings.name);
+    }
+
+    let result = match update(args, settings, &mut report) {
+        Err(err) if recovery::find_corruption(&err).is_some() => {
+            recovery::recover(settings, args.auto_reclone_on_corruption && !args.dry_run, err)
+                .and_then(|_| Ok(Outcome::Pulled { from: None, to: head_sha(&settings.local_path)?, commits: Vec::new() }))
+        }
+        result => result,
+    };
+    let result = result.and_then(|outcome| match &args.write_sha_file {
+        _ if args.dry_run => Ok(outcome),
+        Some(sha_file) if matches!(outcome, Outcome::Pulled { .. }) || args.always_write_sha => {
+            write_sha_file(sha_file, head_sha(&settings.local_path)?)
+                .with_context(|| format!("Failed to write {}", sha_file.display()))?;
+            Ok(outcome)
+        }
+        _ => Ok(outcome),
+    });
+
+    report.new_sha = head_sha(&settings.local_path).ok();
+    report.duration = started.elapsed();
+    report.dry_run = args.dry_run;
+    match result {
+        Ok(outcome) => {
+            let unchanged = match outcome {
+                Outcome::UpToDate { sha } | Outcome::NoChange { sha, .. } => Some(sha),
+                _ => None,
+            };
+            if let (Some(sha), Some(command), false) = (unchanged, &args.on_no_change, settings.disable_hooks) {
+                if args.dry_run {
+                    report.planned_hooks.push("on-no-change");
+                } else {
+                    report.hooks.push(hooks::on_no_change(command, &settings.name, sha));
+                }
+            }
+            report.outcome = Some(outcome);
+        }
+        Err(err) => {
+            match (&args.on_error, settings.disable_hooks) {
+                (Some(_), false) if args.dry_run => report.planned_hooks.push("on-error"),
+                (Some(command), false) => report.hooks.push(hooks::on_error(command, &settings.name, &err)),
+                _ => {}
+            }
+            let err = err.context(format!("Watching {} failed", settings.display_path.display()));
+            report.error = Some(RunError::from_error(&err));
+        }
+    }
+    if let (true, None, Some(hook)) = (args.fail_on_hook_error, &report.error, report.hooks.iter().find(|h| !h.succeeded())) {
+        report.error = Some(RunError::new("hook", format!("The {} hook failed", hook.name)));
+    }
+    trace::event("finish", &[
+        ("outcome", trace::text(report.outcome.as_ref().map_or("error", Outcome::kind))),
+        ("error", report.error.as_ref().map_or("null".to_string(), |e| trace::text(&e.message))),
+        ("duration_ms", report.duration.as_millis().to_string()),
+    ]);
+    report
+}
+
+fn unix_now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// When a commit made at `committed` is younger than `min_age` seconds,
+/// returns the time it becomes old enough to pull.
+fn too_recent(committed: i64, min_age: u64, now: i64) -> Option<i64> {
+    let ready = committed.saturating_add(min_age as i64);
+    (ready > now).then_some(ready)
+}
+
+fn head_sha(local_path: &Path) -> Result<Oid> {
+    Ok(Repository::open(local_path)?.head()?.peel_to_commit()?.id())
+}
+
+/// Does the actual work of a run, filling in the parts of `report` it
+/// learns along the way.
+fn update(args: &Cli, settings: &Settings, report: &mut RunOutcome) -> Result<Outcome> {
+    let path = settings.local_path.as_path();
+    if let Some(change) = args.gerrit_change {
+        return update_gerrit(args, settings, change, report);
+    }
+
+    if args.compare_with_remote_tracking {
+        eprintln!("[{}] Comparing with refs/remotes/{}/{}; no network access", settings.name, settings.remote, settings.branch);
+        report.offline = true;
+    } else if let (refspec, false) = branch_refspec(&Repository::open(path)?.find_remote(&settings.remote)?, &settings.branch) {
+        eprintln!("[{}] Fetch refspecs of remote '{}' do not cover {}; fetching with {}",
+            settings.name, settings.remote, settings.branch, refspec);
+    }
+
+    if args.fetch_all_remotes {
+        report.remote_fetches = remotes::fetch_all(&Repository::open(path)?, &settings.ssh_keys)
+            .context("Failed to list remotes")?;
+    }
+
+    let mut watcher = Watcher::from_args(args, settings);
+    let started = Instant::now();
+    let latest_sha = watcher.detect()?;
+    let connect_time = started.elapsed();
+    report.remote_sha = Some(latest_sha);
+    if let (true, Some(key)) = (settings.ssh_keys.len() > 1, settings.ssh_keys.used()) {
+        eprintln!("[{}] Authenticated with SSH key {}", settings.name, key.display());
+    }
+
+    let has_new_commits = check_for_new_commits(path, latest_sha)
         .context("Failed to check for new commits")?;
+    if !has_new_commits {
+        report.behind = Some(0);
+        return Ok(Outcome::UpToDate { sha: latest_sha });
+    }
+
+    let started = Instant::now();
+    let fetched = watcher.fetch_objects(latest_sha)?;
+    if watcher.connect_timeout.is_some() {
+        eprintln!("[{}] Connect {} ms, transfer {} ms", settings.name, connect_time.as_millis(), started.elapsed().as_millis());
+    }
+    if let Some((throttle, rate)) = watcher.throttle.as_ref().and_then(|t| Some((t, t.average_rate()?))) {
+        eprintln!("[{}] Fetched {} bytes at {} on average", settings.name, throttle.received_bytes(), throttle::format_rate(rate));
+    }
+    let (ahead, behind) = (fetched.ahead, fetched.behind);
+    report.behind = Some(behind);
+    if args.warn_local_ahead && ahead > 0 {
+        eprintln!("[{}] Warning: local is ahead by {} commits; not fast-forwardable", settings.name, ahead);
+        return Ok(Outcome::LocalAhead { sha: latest_sha, ahead });
+    }
+    // The guards below are for incoming commits only
+    if behind == 0 {
+        return Ok(Outcome::NoChange { sha: fetched.local_sha, remote_sha: latest_sha, reason: NoChangeReason::AlreadyContained });
+    }
+    let prefix = args.subtree_prefix.as_deref().map(|p| p.trim_matches('/'));
+    if let Some(prefix) = prefix {
+        let repo = Repository::open(path)?;
+        let current = head_sha(path)?;
+        if sparse::subtree_id(&repo, current, Path::new(prefix))? == sparse::subtree_id(&repo, latest_sha, Path::new(prefix))? {
+            eprintln!("[{}] Nothing changed under {} in {}", settings.name, prefix, latest_sha);
+            return Ok(Outcome::NoChange { sha: current, remote_sha: latest_sha, reason: NoChangeReason::SubtreeUnchanged });
+        }
+    }
+    if let Some(opens) = window::deferred_until(&args.defer_until, window::local_minute_of_day()) {
+        return Ok(Outcome::Deferred { sha: latest_sha, until: window::format_time(opens), reason: DeferReason::DeployWindow });
+    }
+    if let Some(min_age) = args.min_commit_age {
+        let committed = Repository::open(path)?.find_commit(latest_sha)?.time().seconds();
+        if let Some(ready) = too_recent(committed, min_age, unix_now()) {
+            return Ok(Outcome::Deferred {
+                sha: latest_sha,
+                until: format!("{} UTC", output::format_timestamp(ready)),
+                reason: DeferReason::CommitAge,
+            });
+        }
+    }
+    watcher.sparse_paths = args.sparse_path.iter().cloned().chain(prefix.map(str::to_string)).collect();
+    if !watcher.sparse_paths.is_empty() {
+        eprintln!("[{}] Sparse checkout of: {}", settings.name, watcher.sparse_paths.join(", "));
+    }
+    let from = head_sha(path)?;
+    before_checkout(args, settings, from, latest_sha)?;
+    if args.dry_run {
+        if args.pull_confirmation && !args.yes && (confirm::is_interactive() || args.non_interactive == NonInteractive::Skip) {
+            report.planned_guards.push("pull_confirmation");
+        }
+        if args.checkout_helper.is_some() {
+            report.planned_hooks.push("checkout-helper");
+        }
+        let method = if ahead == 0 { PullMethod::FastForward } else { PullMethod::Merge };
+        return Ok(Outcome::WouldPull { from, to: latest_sha, commits: behind, method });
+    }
+    let commits = match args.history_depth_report {
+        Some(limit) => history::incoming_commits(&Repository::open(path)?, from, latest_sha, limit)
+            .context("Failed to summarize incoming commits")?,
+        None => Vec::new(),
+    };
+    if args.pull_confirmation && !args.yes {
+        if !confirm::is_interactive() {
+            if args.non_interactive == NonInteractive::Skip {
+                return Ok(Outcome::Skipped { sha: latest_sha, reason: SkipReason::NotInteractive });
+            }
+        } else if !confirm::ask(&Repository::open(path)?, &settings.name, from, latest_sha)? {
+            return Ok(Outcome::Skipped { sha: latest_sha, reason: SkipReason::Declined });
+        }
+    }
+    let saved_mtimes = match args.preserve_mtimes {
+        true => save_mtimes(path, from, latest_sha)?,
+        false => Vec::new(),
+    };
+    if let Some(command) = &args.checkout_helper {
+        let helper = hooks::checkout_helper(command, &settings.name, path, from, latest_sha);
+        let succeeded = helper.succeeded();
+        report.hooks.push(helper);
+        if !succeeded {
+            bail!("The checkout helper failed");
+        }
+        let head = head_sha(path)?;
+        if head != latest_sha && !Repository::open(path)?.graph_descendant_of(head, latest_sha)? {
+            bail!("The checkout helper left HEAD at {}, which does not contain {}", head, latest_sha);
+        }
+        status::record_pull(&Repository::open(path)?, head, "checkout helper")?;
+    } else {
+        match watcher.apply(latest_sha)? {
+            Applied::AlreadyContained => {
+                return Ok(Outcome::NoChange { sha: from, remote_sha: latest_sha, reason: NoChangeReason::AlreadyContained });
+            }
+            Applied::MergePending => return Ok(Outcome::MergePending { from, to: latest_sha, commits }),
+            Applied::FastForward => {}
+        }
+    }
+
+    let to = head_sha(path)?;
+    after_checkout(args, settings, from, to, &saved_mtimes)?;
+
+    Ok(Outcome::Pulled { from: Some(from), to, commits })
+}
+
+/// Checks and warnings shared by every way of moving HEAD from `from` to
+/// `to`, run before the working tree is touched.
+fn before_checkout(args: &Cli, settings: &Settings, from: Oid, to: Oid) -> Result<()> {
+    let path = settings.local_path.as_path();
+    for warning in worktrees::check(&Repository::open(path)?).context("Worktree check failed")? {
+        eprintln!("[{}] {}", settings.name, warning);
+    }
+    let ignored = ignored::incoming_ignored_paths(&Repository::open(path)?, from, to)
+        .context("Failed to check incoming paths against ignore rules")?;
+    if !ignored.is_empty() {
+        eprintln!("[{}] Incoming tracked files match local ignore rules and will be overwritten:", settings.name);
+        for path in &ignored {
+            eprintln!("  {}", path.display());
+        }
+    }
+    if let Some(required) = args.min_free_space {
+        disk::ensure_free_space(path, required)?;
+    }
+    Ok(())
+}
 
-    if has_new_commits {
-        pull_repo(&path, &remote, &branch).context("Failed to pull new commits")?;        
+fn save_mtimes(path: &Path, from: Oid, to: Oid) -> Result<Vec<mtimes::Saved>> {
+    let repo = Repository::open(path)?;
+    let (old, new) = (repo.find_commit(from)?.tree()?, repo.find_commit(to)?.tree()?);
+    mtimes::snapshot(&repo, &old, &new).context("Failed to record file modification times")
+}
+
+/// The post-pull steps, run once HEAD has moved from `from` to `to`.
+fn after_checkout(args: &Cli, settings: &Settings, from: Oid, to: Oid, saved_mtimes: &[mtimes::Saved]) -> Result<()> {
+    let path = settings.local_path.as_path();
+    if args.preserve_mtimes {
+        let repo = Repository::open(path)?;
+        let preserved = mtimes::restore(&repo, &repo.find_commit(to)?.tree()?, saved_mtimes);
+        eprintln!("[{}] Preserved the mtimes of {} unchanged files", settings.name, preserved);
+    }
+    if args.post_merge_fsck {
+        let checked = integrity::verify_commit_tree(&Repository::open(path)?, to)
+            .context("Post-merge integrity check failed")?;
+        eprintln!("[{}] Integrity check passed ({} objects)", settings.name, checked);
+    }
+    if let Some(scope) = args.clean_after_pull {
+        let removed = clean::clean(&Repository::open(path)?, scope, &args.clean_exclude)
+            .context("Failed to clean the working tree")?;
+        eprintln!("[{}] Cleaned {} files", settings.name, removed.len());
+        for path in &removed {
+            eprintln!("  {}", path.display());
+        }
+    }
+    if args.fail_on_dirty_after {
+        let modified = clean::modified_tracked_files(&Repository::open(path)?)
+            .context("Failed to check the working tree")?;
+        if !modified.is_empty() {
+            let paths: Vec<String> = modified.iter().map(|p| p.display().to_string()).collect();
+            bail!("Tracked files differ from {} after the pull: {}", to, paths.join(", "));
+        }
+    }
+    if args.commit_graph {
+        let started = Instant::now();
+        match maintenance::write_commit_graph(path) {
+            Ok(()) => eprintln!("[{}] Commit-graph updated in {} ms", settings.name, started.elapsed().as_millis()),
+            Err(err) => eprintln!("[{}] Warning: {:#}", settings.name, err),
+        }
+    }
+    if args.checkout_file_mode.is_some() || args.checkout_owner.is_some() {
+        let updated = permissions::apply(&Repository::open(path)?, &settings.name, from, to, args.checkout_file_mode, args.checkout_owner)
+            .context("Failed to apply checkout permissions")?;
+        eprintln!("[{}] Updated permissions of {} files", settings.name, updated);
     }
-    
-   
     Ok(())
 }
 
+/// `update` for `--gerrit-change`: moves a detached HEAD to the wanted
+/// patchset whenever it changes.
+fn update_gerrit(args: &Cli, settings: &Settings, change: ChangeRef, report: &mut RunOutcome) -> Result<Outcome> {
+    let path = settings.local_path.as_path();
+    let repo = Repository::open(path)?;
+    let patchset = gerrit::advertised_patchset(&repo, &settings.ssh_keys, &settings.remote, change)
+        .context("Failed to read the change's patchsets")?;
+    report.remote_sha = Some(patchset.sha);
+    eprintln!("[{}] Change {} patchset {} is at {}; HEAD is at {}", settings.name, change.change, patchset.number, patchset.sha,
+        patchset.checked_out.map_or("no patchset of this change".to_string(), |n| format!("patchset {}", n)));
+
+    let from = head_sha(path)?;
+    if from == patchset.sha {
+        report.behind = Some(0);
+        return Ok(Outcome::UpToDate { sha: from });
+    }
+    if let Some(opens) = window::deferred_until(&args.defer_until, window::local_minute_of_day()) {
+        return Ok(Outcome::Deferred { sha: patchset.sha, until: window::format_time(opens), reason: DeferReason::DeployWindow });
+    }
+
+    gerrit::fetch_patchset(&repo, &settings.ssh_keys, &settings.remote, change, patchset.number)
+        .context("Failed to fetch the patchset")?;
+    let (_, behind) = repo.graph_ahead_behind(from, patchset.sha)?;
+    report.behind = Some(behind);
+    before_checkout(args, settings, from, patchset.sha)?;
+    if args.dry_run {
+        return Ok(Outcome::WouldPull { from, to: patchset.sha, commits: behind, method: PullMethod::Checkout });
+    }
+    let commits = match args.history_depth_report {
+        Some(limit) => history::incoming_commits(&repo, from, patchset.sha, limit)
+            .context("Failed to summarize incoming commits")?,
+        None => Vec::new(),
+    };
+    let saved_mtimes = match args.preserve_mtimes {
+        true => save_mtimes(path, from, patchset.sha)?,
+        false => Vec::new(),
+    };
+    gerrit::checkout_detached(&repo, patchset.sha, patchset.number).context("Failed to check out the patchset")?;
+    after_checkout(args, settings, from, patchset.sha, &saved_mtimes)?;
+    Ok(Outcome::Pulled { from: Some(from), to: patchset.sha, commits })
+}
+
+/// Writes `sha` and a newline through a temporary file, so readers never
+/// see a partially written SHA.
+fn write_sha_file(sha_file: &Path, sha: Oid) -> std::io::Result<()> {
+    let mut tmp_name = sha_file.as_os_str().to_owned();
+    tmp_name.push(".tmp");
+    let tmp = PathBuf::from(tmp_name);
+
+    std::fs::write(&tmp, format!("{}\n", sha))?;
+    std::fs::rename(&tmp, sha_file)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    
-    fn _test_values() -> Cli{
-        let env_path = Path::new(".local.env");
-        dotenv::from_path(env_path).ok();
-        
-        let local_path = PathBuf::from(env::var("LOCAL_PATH").expect("Local path not set"));
-        let remote = env::var("REMOTE").expect("Remote not set");
-        let branch = env::var("BRANCH").expect("Branch not set");
-        let ssh_key_path = PathBuf::from(env::var("SSH_KEY_PATH").expect("SSH key path not set"));
-        
-        // let path_buf = PathBuf::from(local_path);
-        let path = local_path.as_path();
-        let ssh_key_path = ssh_key_path.as_path();
-        
-        
-        Cli {
-            local_path: Some(&local_path),
-            remote: Some("github".to_string()),
-            branch: Some("master".to_string()),
-            ssh_key_path: Some(PathBuf::from("test_key")),
-            env_file: None,
-        }
-        // TODO: break out args||env vars into separate function so that it can be reused in tests
-        // TODO: too many values being passed to functions, need to refactor
-        // TODO: mock git2::Repository for tests
-        // TODO: mock git2::Remote for tests
-        
-    }
-    
+    use git2::{RepositoryInitOptions, Signature};
+    use std::fs;
+
+    /// An upstream repository and a clone of it, created under the system
+    /// temp dir so tests never touch a real remote.
+    pub(crate) struct TestRepos {
+        pub root: PathBuf,
+        pub upstream: PathBuf,
+        pub local: PathBuf,
+    }
+
+    impl Drop for TestRepos {
+        fn drop(&mut self) {
+            fs::remove_dir_all(&self.root).ok();
+        }
+    }
+
+    pub(crate) fn setup_repos(name: &str) -> TestRepos {
+        let root = env::temp_dir().join(format!("repo-watcher-{}-{}", name, std::process::id()));
+        fs::remove_dir_all(&root).ok();
+        fs::create_dir_all(&root).unwrap();
+
+        let upstream = root.join("upstream");
+        let mut opts = RepositoryInitOptions::new();
+        opts.initial_head("master");
+        let repo = Repository::init_opts(&upstream, &opts).unwrap();
+        commit_file(&repo, "README.md", "first\n", "Initial commit");
+
+        let local = root.join("local");
+        Repository::clone(upstream.to_str().unwrap(), &local).unwrap();
+
+        TestRepos { root, upstream, local }
+    }
+
+    pub(crate) fn test_keys() -> SshKeys {
+        SshKeys::new(vec![PathBuf::from("test_key")])
+    }
+
+    pub(crate) fn settings_for(repos: &TestRepos) -> Settings {
+        Settings {
+            name: "test".to_string(),
+            local_path: repos.local.clone(),
+            display_path: repos.local.clone(),
+            remote: "origin".to_string(),
+            branch: "master".to_string(),
+            ssh_keys: test_keys(),
+            disable_hooks: false,
+        }
+    }
+
+    pub(crate) fn commit_file(repo: &Repository, file: &str, contents: &str, message: &str) -> Oid {
+        let workdir = repo.workdir().unwrap();
+        fs::write(workdir.join(file), contents).unwrap();
+
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new(file)).unwrap();
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+
+        let sig = Signature::now("Test", "test@example.com").unwrap();
+        let parent = repo.head().ok().and_then(|h| h.peel_to_commit().ok());
+        let parents: Vec<&git2::Commit> = parent.iter().collect();
+        repo.commit(Some("HEAD"), &sig, &sig, message, &tree, &parents).unwrap()
+    }
+
     #[test]
     fn test_pull_repo() {
-        let args = _test_values();
+        let repos = setup_repos("pull");
+        let upstream = Repository::open(&repos.upstream).unwrap();
+        let latest_sha = commit_file(&upstream, "README.md", "second\n", "Second commit");
+        fetch_commit(&repos.local, &mut test_keys().fetch_options(), "origin", "master", latest_sha).unwrap();
 
-        let result = pull_repo(&args);
+        let result = pull_repo(&repos.local, latest_sha, &[]);
 
         assert!(result.is_ok());
+        let local = Repository::open(&repos.local).unwrap();
+        assert_eq!(latest_sha, local.head().unwrap().target().unwrap());
+        assert_eq!("second\n", fs::read_to_string(repos.local.join("README.md")).unwrap());
     }
 
     #[test]
-    fn test_fetch_latest_commit_sha() {
-        let args = _test_values();
-        let expected_sha = "449022de3b3ebcfbbbb010f2ca91f724df03b33e";
+    fn test_pull_repo_merges_diverged_history() {
+        let repos = setup_repos("pull-merge");
+        let upstream = Repository::open(&repos.upstream).unwrap();
+        let latest_sha = commit_file(&upstream, "upstream.txt", "upstream\n", "Upstream commit");
+        let local = Repository::open(&repos.local).unwrap();
+        commit_file(&local, "local.txt", "local\n", "Local commit");
+        fetch_commit(&repos.local, &mut test_keys().fetch_options(), "origin", "master", latest_sha).unwrap();
 
+        let local_sha = head_sha(&repos.local).unwrap();
+
+        assert_eq!(Applied::MergePending, pull_repo(&repos.local, latest_sha, &[]).unwrap());
+
+        assert_eq!(local_sha, head_sha(&repos.local).unwrap());
+        assert!(repos.local.join("upstream.txt").exists());
+        assert_eq!(git2::RepositoryState::Merge, local.state());
+    }
+
+    #[test]
+    fn test_update_keeps_pending_merge() {
+        let repos = setup_repos("pending-merge");
+        let settings = settings_for(&repos);
+        let args = Cli::parse_from(["repo-watcher"]);
+        let upstream = Repository::open(&repos.upstream).unwrap();
+        let latest_sha = commit_file(&upstream, "upstream.txt", "upstream\n", "Upstream commit");
+        let local = Repository::open(&repos.local).unwrap();
+        let local_sha = commit_file(&local, "local.txt", "local\n", "Local commit");
+        let mut report = RunOutcome::new("pending-merge");
+
+        let pending = Outcome::MergePending { from: local_sha, to: latest_sha, commits: Vec::new() };
+        assert_eq!(pending, update(&args, &settings, &mut report).unwrap());
+        assert_eq!(pending, update(&args, &settings, &mut report).unwrap());
+        assert_eq!(local_sha, head_sha(&repos.local).unwrap());
+
+        // A newer tip can't be merged on top of the unfinished merge
+        commit_file(&upstream, "upstream.txt", "newer\n", "Newer upstream commit");
+        let err = update(&args, &settings, &mut report).unwrap_err();
+        assert!(format!("{:#}", err).contains("merge is in progress"), "{:#}", err);
+        assert_eq!(local_sha, head_sha(&repos.local).unwrap());
+        assert_eq!("upstream\n", fs::read_to_string(repos.local.join("upstream.txt")).unwrap());
+    }
+
+    #[test]
+    fn test_advertised_commit_sha() {
+        let repos = setup_repos("advertised");
+        let upstream = Repository::open(&repos.upstream).unwrap();
+        let expected_sha = commit_file(&upstream, "README.md", "second\n", "Second commit");
+
+        let actual_sha = advertised_commit_sha(&repos.local, &test_keys(), "origin", "master").unwrap();
 
-        let actual_sha = match fetch_latest_commit_sha(&args) {
-            Ok(sha) => sha,
-            Err(e) => {
-                println!("Error occurred: {}", e); // Print error message
-                panic!("Test failed due to error: {}", e); // Panic with error message
-            },
-        };
         assert_eq!(expected_sha, actual_sha);
+        // Detection alone must not download the new commit
+        assert!(Repository::open(&repos.local).unwrap().find_commit(expected_sha).is_err());
+        assert!(advertised_commit_sha(&repos.local, &test_keys(), "origin", "missing").is_err());
+    }
+
+    #[test]
+    fn test_fetch_latest_commit_sha() {
+        let repos = setup_repos("fetch");
+        let upstream = Repository::open(&repos.upstream).unwrap();
+        let expected_sha = commit_file(&upstream, "README.md", "second\n", "Second commit");
+
+        let actual_sha = fetch_latest_commit_sha(&repos.local, &mut test_keys().fetch_options(), "origin", "master")
+            .expect("fetch failed");
+        assert_eq!(expected_sha, actual_sha);
+    }
+
+    #[test]
+    fn test_fetch_commit_moved_branch() {
+        let repos = setup_repos("moved-branch");
+        let gone = Oid::from_str("1111111111111111111111111111111111111111").unwrap();
+
+        let err = fetch_commit(&repos.local, &mut test_keys().fetch_options(), "origin", "master", gone).unwrap_err();
+
+        assert!(err.to_string().contains("branch moved during the fetch"));
+        assert!(recovery::find_corruption(&err).is_none());
+    }
+
+    #[test]
+    fn test_update_outcomes() {
+        let repos = setup_repos("update");
+        let settings = settings_for(&repos);
+        let args = Cli::parse_from(["repo-watcher"]);
+        let initial = head_sha(&repos.local).unwrap();
+
+        let mut report = RunOutcome::new("update");
+        assert_eq!(Outcome::UpToDate { sha: initial }, update(&args, &settings, &mut report).unwrap());
+
+        let upstream = Repository::open(&repos.upstream).unwrap();
+        let latest_sha = commit_file(&upstream, "README.md", "second\n", "Second commit");
+        assert_eq!(Outcome::Pulled { from: Some(initial), to: latest_sha, commits: Vec::new() }, update(&args, &settings, &mut report).unwrap());
+        assert_eq!(Some(latest_sha), report.remote_sha);
+        assert_eq!(Some(1), report.behind);
+    }
+
+    #[test]
+    fn test_update_compare_with_remote_tracking() {
+        let repos = setup_repos("remote-tracking");
+        let settings = settings_for(&repos);
+        let args = Cli::parse_from(["repo-watcher", "--compare-with-remote-tracking"]);
+        let initial = head_sha(&repos.local).unwrap();
+        let upstream = Repository::open(&repos.upstream).unwrap();
+        let fetched_sha = commit_file(&upstream, "README.md", "second\n", "Second commit");
+        fetch_commit(&repos.local, &mut test_keys().fetch_options(), "origin", "master", fetched_sha).unwrap();
+        commit_file(&upstream, "README.md", "third\n", "Not fetched yet");
+        // Any network access would now fail
+        Repository::open(&repos.local).unwrap().remote_set_url("origin", "git://127.0.0.1:1/missing").unwrap();
+        let mut report = RunOutcome::new("remote-tracking");
+
+        assert_eq!(Outcome::Pulled { from: Some(initial), to: fetched_sha, commits: Vec::new() }, update(&args, &settings, &mut report).unwrap());
+        assert!(report.offline);
+    }
+
+    #[test]
+    fn test_update_pull_confirmation() {
+        let repos = setup_repos("confirmation");
+        let settings = settings_for(&repos);
+        let upstream = Repository::open(&repos.upstream).unwrap();
+        let latest_sha = commit_file(&upstream, "README.md", "second\n", "Second commit");
+        let mut report = RunOutcome::new("confirmation");
+        if confirm::is_interactive() {
+            // The prompt would wait for an answer
+            return;
+        }
+
+        let args = Cli::parse_from(["repo-watcher", "--pull-confirmation", "--non-interactive", "skip"]);
+        assert_eq!(Outcome::Skipped { sha: latest_sha, reason: SkipReason::NotInteractive }, update(&args, &settings, &mut report).unwrap());
+
+        let args = Cli::parse_from(["repo-watcher", "--pull-confirmation", "--non-interactive", "skip", "--yes"]);
+        assert!(matches!(update(&args, &settings, &mut report).unwrap(), Outcome::Pulled { .. }));
+    }
+
+    #[test]
+    fn test_update_dry_run() {
+        let repos = setup_repos("dry-run");
+        let settings = settings_for(&repos);
+        let args = Cli::parse_from(["repo-watcher", "--dry-run"]);
+        let initial = head_sha(&repos.local).unwrap();
+        let upstream = Repository::open(&repos.upstream).unwrap();
+        commit_file(&upstream, "README.md", "second\n", "Second commit");
+        let latest_sha = commit_file(&upstream, "README.md", "third\n", "Third commit");
+        let mut report = RunOutcome::new("dry-run");
+
+        let planned = Outcome::WouldPull { from: initial, to: latest_sha, commits: 2, method: PullMethod::FastForward };
+        assert_eq!(planned, update(&args, &settings, &mut report).unwrap());
+        assert_eq!(planned, update(&args, &settings, &mut report).unwrap());
+        assert_eq!(initial, head_sha(&repos.local).unwrap());
+        assert!(report.planned_hooks.is_empty() && report.planned_guards.is_empty());
+
+        let args = Cli::parse_from(["repo-watcher", "--dry-run", "--checkout-helper", "exit 1",
+            "--pull-confirmation", "--non-interactive", "skip"]);
+        let mut report = RunOutcome::new("dry-run");
+        assert_eq!(planned, update(&args, &settings, &mut report).unwrap());
+        assert_eq!(vec!["checkout-helper"], report.planned_hooks);
+        assert_eq!(vec!["pull_confirmation"], report.planned_guards);
+    }
+
+    #[test]
+    fn test_update_warn_local_ahead() {
+        let repos = setup_repos("local-ahead");
+        let settings = settings_for(&repos);
+        let args = Cli::parse_from(["repo-watcher", "--warn-local-ahead"]);
+        let local = Repository::open(&repos.local).unwrap();
+        let remote_sha = head_sha(&repos.upstream).unwrap();
+        let mut report = RunOutcome::new("local-ahead");
+
+        let local_sha = commit_file(&local, "local.txt", "local\n", "Commit on the deploy box");
+        assert_eq!(Outcome::LocalAhead { sha: remote_sha, ahead: 1 }, update(&args, &settings, &mut report).unwrap());
+        assert_eq!(local_sha, head_sha(&repos.local).unwrap());
+
+        // Without the flag there is nothing to merge either
+        let args = Cli::parse_from(["repo-watcher"]);
+        let contained = Outcome::NoChange { sha: local_sha, remote_sha, reason: NoChangeReason::AlreadyContained };
+        assert_eq!(contained, update(&args, &settings, &mut report).unwrap());
+
+        // Nor is a tip HEAD already contains waiting to age
+        let args = Cli::parse_from(["repo-watcher", "--min-commit-age", "86400"]);
+        assert_eq!(contained, update(&args, &settings, &mut report).unwrap());
+    }
+
+    #[test]
+    fn test_update_refuses_branch_in_other_worktree() {
+        let repos = setup_repos("worktree-refusal");
+        let settings = settings_for(&repos);
+        let args = Cli::parse_from(["repo-watcher"]);
+        let initial = head_sha(&repos.local).unwrap();
+        let local = Repository::open(&repos.local).unwrap();
+        local.worktree("preview", &repos.root.join("preview"), None).unwrap();
+        // What `git checkout master` inside the worktree would leave
+        fs::write(local.path().join("worktrees/preview/HEAD"), "ref: refs/heads/master\n").unwrap();
+        let upstream = Repository::open(&repos.upstream).unwrap();
+        commit_file(&upstream, "README.md", "second\n", "Second commit");
+        let mut report = RunOutcome::new("worktree-refusal");
+
+        let err = update(&args, &settings, &mut report).unwrap_err();
+
+        assert!(format!("{:#}", err).contains("refusing to update it"), "{:#}", err);
+        assert_eq!(initial, head_sha(&repos.local).unwrap());
+        assert_eq!("first\n", fs::read_to_string(repos.local.join("README.md")).unwrap());
+    }
+
+    #[test]
+    fn test_update_defer_window() {
+        let repos = setup_repos("defer-window");
+        let settings = settings_for(&repos);
+        let now = window::local_minute_of_day();
+        let closed = format!("{}-{}", window::format_time((now + 2) % 1440), window::format_time((now + 3) % 1440));
+        let args = Cli::parse_from(["repo-watcher", "--defer-until", &closed]);
+        let remote_sha = head_sha(&repos.upstream).unwrap();
+        let mut report = RunOutcome::new("defer-window");
+
+        let local_sha = commit_file(&Repository::open(&repos.local).unwrap(), "local.txt", "local\n", "Commit on the deploy box");
+        let contained = Outcome::NoChange { sha: local_sha, remote_sha, reason: NoChangeReason::AlreadyContained };
+        assert_eq!(contained, update(&args, &settings, &mut report).unwrap());
+
+        let latest_sha = commit_file(&Repository::open(&repos.upstream).unwrap(), "README.md", "second\n", "Second commit");
+        assert!(matches!(update(&args, &settings, &mut report).unwrap(),
+            Outcome::Deferred { sha, reason: DeferReason::DeployWindow, .. } if sha == latest_sha));
+        assert_eq!(local_sha, head_sha(&repos.local).unwrap());
+    }
+
+    #[test]
+    fn test_update_subtree_prefix() {
+        let repos = setup_repos("subtree-prefix");
+        let settings = settings_for(&repos);
+        let args = Cli::parse_from(["repo-watcher", "--subtree-prefix", "service/"]);
+        let upstream = Repository::open(&repos.upstream).unwrap();
+        let initial = head_sha(&repos.local).unwrap();
+        let mut report = RunOutcome::new("subtree");
+
+        let outside = commit_file(&upstream, "README.md", "outside\n", "Change outside the prefix");
+        let unchanged = Outcome::NoChange { sha: initial, remote_sha: outside, reason: NoChangeReason::SubtreeUnchanged };
+        assert_eq!(unchanged, update(&args, &settings, &mut report).unwrap());
+
+        fs::create_dir_all(repos.upstream.join("service")).unwrap();
+        let latest_sha = commit_file(&upstream, "service/main.txt", "service\n", "Add service");
+        assert_eq!(Outcome::Pulled { from: Some(initial), to: latest_sha, commits: Vec::new() }, update(&args, &settings, &mut report).unwrap());
+        assert!(repos.local.join("service/main.txt").exists());
+        assert!(!repos.local.join("README.md").exists());
+        assert!(status::status(&settings).unwrap().clean);
+
+        // The files left out of the subtree are not local modifications
+        let next_sha = commit_file(&upstream, "service/main.txt", "changed\n", "Change service");
+        let args = Cli::parse_from(["repo-watcher", "--subtree-prefix", "service/", "--fail-on-dirty-after"]);
+        assert_eq!(Outcome::Pulled { from: Some(latest_sha), to: next_sha, commits: Vec::new() }, update(&args, &settings, &mut report).unwrap());
+    }
+
+    #[test]
+    fn test_run_records_failure() {
+        let repos = setup_repos("run-failure");
+        let args = Cli::parse_from(["repo-watcher", "--local-path", repos.local.to_str().unwrap(), "--remote", "missing", "--branch", "master", "-s", "/dev/null"]);
+
+        let report = run(&args, &Settings::from_args(&args).unwrap());
+
+        assert!(report.outcome.is_none());
+        assert_eq!(report.old_sha, report.new_sha);
+        assert_eq!(1, report.exit_code(&[]));
+        assert!(report.error.unwrap().message.contains("Watching"));
+    }
+
+    #[test]
+    fn test_connect_timeout() {
+        let repos = setup_repos("connect-timeout");
+        let expected_sha = Repository::open(&repos.upstream).unwrap().head().unwrap().target().unwrap();
+        let timeout = Duration::from_secs(5);
+
+        let sha = advertised_commit_sha_within(&repos.local, &test_keys(), "origin", "master", timeout).unwrap();
+        assert_eq!(expected_sha, sha);
+
+        // A listener that never answers stands in for a dead host
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("git://{}/repo", listener.local_addr().unwrap());
+        Repository::open(&repos.local).unwrap().remote("silent", &url).unwrap();
+        let err = advertised_commit_sha_within(&repos.local, &test_keys(), "silent", "master", Duration::from_millis(200)).unwrap_err();
+        assert!(err.message().starts_with("Timed out"), "{}", err);
+
+        // The object fetch opens a connection of its own, with the same limit
+        let latest_sha = commit_file(&Repository::open(&repos.upstream).unwrap(), "README.md", "second\n", "Second commit");
+        fetch_commit_within(&repos.local, &test_keys(), None, "origin", "master", latest_sha, timeout).unwrap();
+        assert!(Repository::open(&repos.local).unwrap().find_commit(latest_sha).is_ok());
+        let err = fetch_commit_within(&repos.local, &test_keys(), None, "silent", "master", latest_sha, Duration::from_millis(200)).unwrap_err();
+        assert!(err.to_string().starts_with("Timed out"), "{}", err);
+
+        // A full fetch has no separate connect phase to limit
+        assert!(Cli::try_parse_from(["repo-watcher", "--connect-timeout", "5", "--full-fetch"]).is_err());
+    }
+
+    #[test]
+    fn test_fetch_with_narrow_refspec() {
+        let repos = setup_repos("refspec");
+        let upstream = Repository::open(&repos.upstream).unwrap();
+        let latest_sha = commit_file(&upstream, "README.md", "second\n", "Second commit");
+        let local = Repository::open(&repos.local).unwrap();
+        let mut config = local.config().unwrap();
+        config.set_str("remote.origin.fetch", "+refs/heads/release:refs/remotes/origin/release").unwrap();
+
+        let (refspec, covered) = branch_refspec(&local.find_remote("origin").unwrap(), "master");
+        assert!(!covered);
+        fetch_commit(&repos.local, &mut test_keys().fetch_options(), "origin", "master", latest_sha).unwrap();
+
+        assert_eq!("+refs/heads/master:refs/remotes/origin/master", refspec);
+        assert_eq!(latest_sha, local.refname_to_id("refs/remotes/origin/master").unwrap());
+        assert_eq!("+refs/heads/release:refs/remotes/origin/release", config.get_string("remote.origin.fetch").unwrap());
+    }
+
+    #[test]
+    fn test_too_recent() {
+        assert_eq!(Some(1_060), too_recent(1_000, 60, 1_030));
+        assert_eq!(None, too_recent(1_000, 60, 1_060));
+        assert_eq!(None, too_recent(1_000, 0, 1_000));
+    }
+
+    #[test]
+    fn test_write_sha_file() {
+        let repos = setup_repos("sha-file");
+        let sha_file = repos.root.join("DEPLOYED_SHA");
+        let sha = Repository::open(&repos.local).unwrap().head().unwrap().target().unwrap();
+
+        write_sha_file(&sha_file, sha).unwrap();
+
+        assert_eq!(format!("{}\n", sha), fs::read_to_string(&sha_file).unwrap());
+        assert!(!repos.root.join("DEPLOYED_SHA.tmp").exists());
+    }
+
+    #[test]
+    fn test_default_repo_name() {
+        assert_eq!("x7", default_repo_name(Path::new("/srv/deploy/x7")));
+        assert_eq!("api", default_repo_name(Path::new("/srv/api/.git")));
+    }
+
+    #[test]
+    fn test_normalize_branch() {
+        let remotes = vec!["origin".to_string(), "upstream".to_string()];
+        let normalize = |branch| normalize_branch(branch, &remotes).unwrap();
+
+        assert_eq!((None, "main".to_string()), normalize("main"));
+        assert_eq!((None, "main".to_string()), normalize("refs/heads/main"));
+        assert_eq!((None, "feature/x".to_string()), normalize("feature/x"));
+        assert_eq!((Some("upstream".to_string()), "main".to_string()), normalize("upstream/main"));
+        assert_eq!((Some("origin".to_string()), "release/1.0".to_string()), normalize("refs/remotes/origin/release/1.0"));
+        assert!(normalize_branch("refs/tags/v1.0", &remotes).is_err());
+        assert!(normalize_branch("refs/remotes/origin", &remotes).is_err());
+        assert!(normalize_branch("main..old", &remotes).is_err());
+        assert!(normalize_branch("", &remotes).is_err());
+    }
+
+    #[test]
+    fn test_update_gerrit_change() {
+        let repos = setup_repos("gerrit");
+        let settings = settings_for(&repos);
+        let upstream = Repository::open(&repos.upstream).unwrap();
+        let initial = head_sha(&repos.local).unwrap();
+        let first = commit_file(&upstream, "README.md", "patchset 1\n", "Change");
+        upstream.reference("refs/changes/45/12345/1", first, false, "").unwrap();
+        upstream.reference("refs/heads/master", initial, true, "").unwrap();
+        let mut report = RunOutcome::new("gerrit");
+        assert!(Cli::try_parse_from(["repo-watcher", "--gerrit-change", "12345", "--warn-local-ahead"]).is_err());
+
+        let dry_run = Cli::parse_from(["repo-watcher", "--gerrit-change", "12345", "--dry-run"]);
+        assert_eq!(Outcome::WouldPull { from: initial, to: first, commits: 1, method: PullMethod::Checkout }, update(&dry_run, &settings, &mut report).unwrap());
+        let args = Cli::parse_from(["repo-watcher", "--gerrit-change", "12345"]);
+        assert_eq!(Outcome::Pulled { from: Some(initial), to: first, commits: Vec::new() }, update(&args, &settings, &mut report).unwrap());
+        assert_eq!(Outcome::UpToDate { sha: first }, update(&args, &settings, &mut report).unwrap());
+
+        upstream.set_head_detached(initial).unwrap();
+        let second = commit_file(&upstream, "README.md", "patchset 2\n", "Change, amended");
+        upstream.reference("refs/changes/45/12345/2", second, false, "").unwrap();
+        assert_eq!(Outcome::WouldPull { from: first, to: second, commits: 1, method: PullMethod::Checkout }, update(&dry_run, &settings, &mut report).unwrap());
+        assert_eq!(Outcome::Pulled { from: Some(first), to: second, commits: Vec::new() }, update(&args, &settings, &mut report).unwrap());
+        let local = Repository::open(&repos.local).unwrap();
+        assert!(local.head_detached().unwrap());
+        assert_eq!(initial, local.refname_to_id("refs/heads/master").unwrap());
+        assert_eq!("patchset 2\n", fs::read_to_string(repos.local.join("README.md")).unwrap());
+        assert!(status::status(&settings).unwrap().last_pull.is_some());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_update_checkout_helper() {
+        let repos = setup_repos("checkout-helper");
+        let settings = settings_for(&repos);
+        let initial = head_sha(&repos.local).unwrap();
+        let upstream = Repository::open(&repos.upstream).unwrap();
+        let latest_sha = commit_file(&upstream, "README.md", "second\n", "Second commit");
+        let mut report = RunOutcome::new("checkout-helper");
+
+        let failing = Cli::parse_from(["repo-watcher", "--checkout-helper", "exit 1"]);
+        assert!(update(&failing, &settings, &mut report).is_err());
+        assert_eq!(initial, head_sha(&repos.local).unwrap());
+
+        let helper = r#"git -C "$REPO_WATCHER_LOCAL_PATH" merge --ff-only -q "$REPO_WATCHER_SHA""#;
+        let args = Cli::parse_from(["repo-watcher", "--checkout-helper", helper]);
+        assert_eq!(Outcome::Pulled { from: Some(initial), to: latest_sha, commits: Vec::new() }, update(&args, &settings, &mut report).unwrap());
+        assert_eq!(vec![Some(1), Some(0)], report.hooks.iter().map(|hook| hook.exit_code).collect::<Vec<_>>());
+        assert!(status::status(&settings).unwrap().last_pull.is_some());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_update_detect_command() {
+        let repos = setup_repos("detect-command");
+        let settings = settings_for(&repos);
+        let initial = head_sha(&repos.local).unwrap();
+        let upstream = Repository::open(&repos.upstream).unwrap();
+        let detected_sha = commit_file(&upstream, "README.md", "second\n", "Second commit");
+        commit_file(&upstream, "README.md", "third\n", "Not announced yet");
+        let mut report = RunOutcome::new("detect-command");
+
+        let args = Cli::parse_from(["repo-watcher", "--detect-command", &format!("echo {}", detected_sha)]);
+        assert_eq!(Outcome::Pulled { from: Some(initial), to: detected_sha, commits: Vec::new() }, update(&args, &settings, &mut report).unwrap());
+
+        let failing = Cli::parse_from(["repo-watcher", "--detect-command", "exit 1"]);
+        assert!(update(&failing, &settings, &mut report).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_on_no_change_hook() {
+        let repos = setup_repos("on-no-change");
+        let out = repos.root.join("heartbeat");
+        let command = format!("printf '%s' \"$REPO_WATCHER_SHA\" > '{}'; exit 2", out.display());
+        let args = Cli::parse_from(["repo-watcher", "--on-no-change", &command]);
+
+        let report = run(&args, &settings_for(&repos));
+
+        assert_eq!(None, report.error);
+        assert_eq!(Some(Some(2)), report.hooks.first().map(|hook| hook.exit_code));
+        assert_eq!(head_sha(&repos.local).unwrap().to_string(), fs::read_to_string(&out).unwrap());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlinked_local_path() {
+        let repos = setup_repos("symlink");
+        let link = repos.root.join("link");
+        std::os::unix::fs::symlink(&repos.local, &link).unwrap();
+        let upstream = Repository::open(&repos.upstream).unwrap();
+        let latest_sha = commit_file(&upstream, "README.md", "second\n", "Second commit");
+        let args = Cli::parse_from(["repo-watcher", "-l", link.to_str().unwrap(), "-r", "origin", "-b", "master", "-s", "test_key"]);
+
+        let settings = Settings::from_args(&args).unwrap();
+        assert_eq!(fs::canonicalize(&repos.local).unwrap(), settings.local_path);
+        assert_eq!(link, settings.display_path);
+        assert_eq!("link", settings.name);
+
+        let report = run(&args, &settings);
+        assert_eq!(None, report.error);
+        assert_eq!(Some(latest_sha), report.new_sha);
+        assert!(status::is_clean(&Repository::open(&link).unwrap()).unwrap());
+    }
+
+    #[test]
+    fn test_is_truthy() {
+        assert!(is_truthy("1"));
+        assert!(is_truthy("yes"));
+        assert!(!is_truthy("0"));
+        assert!(!is_truthy("False"));
+        assert!(!is_truthy(""));
     }
 
     #[test]
     fn test_check_for_new_commits() {
-        let local_path = "test_repo.git";
-        let path = Path::new(local_path);
-        let latest_sha = "449022de3b3ebcfbbbb010f2ca91f724df03b33e";
-        // let branch = "master";
-        
-        let has_new_commits = check_for_new_commits(path, latest_sha).unwrap();
+        let repos = setup_repos("check");
+        let local = Repository::open(&repos.local).unwrap();
+        let latest_sha = local.head().unwrap().target().unwrap();
 
-        assert_eq!(false, has_new_commits);
+        let has_new_commits = check_for_new_commits(&repos.local, latest_sha).unwrap();
+
+        assert!(!has_new_commits);
     }
-}
\ No newline at end of file
+}
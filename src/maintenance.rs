@@ -0,0 +1,33 @@
+//! Housekeeping that keeps large watched repositories fast.
+
+use anyhow::{bail, Result};
+use std::path::Path;
+use std::process::Command;
+
+/// Writes or refreshes the commit-graph file for everything reachable from
+/// the refs, with `git commit-graph write`. git2 has no commit-graph writer.
+pub fn write_commit_graph(local_path: &Path) -> Result<()> {
+    let output = Command::new("git")
+        .args(["commit-graph", "write", "--reachable", "--no-progress"])
+        .current_dir(local_path)
+        .output()?;
+    if !output.status.success() {
+        bail!("git commit-graph write failed: {}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::setup_repos;
+
+    #[test]
+    fn test_write_commit_graph() {
+        let repos = setup_repos("commit-graph");
+
+        write_commit_graph(&repos.local).unwrap();
+
+        assert!(repos.local.join(".git/objects/info/commit-graph").exists());
+    }
+}
@@ -0,0 +1,94 @@
+//! Support for `.gitattributes` merge settings that libgit2 does not apply
+//! on its own.
+//!
+//! libgit2 honours the built-in `text`, `binary` and `union` merge drivers
+//! but has no `ours` driver; an unknown driver name silently falls back to a
+//! text merge. `resolve_ours` makes paths marked `merge=ours` behave as they
+//! do with git: when both sides touched the file, the local version wins.
+
+use git2::{AttrCheckFlags, Commit, Error, Index, IndexEntry, IndexTime, Repository};
+use std::path::{Path, PathBuf};
+
+const MERGE_ATTR: &str = "merge";
+
+/// Rewrites `index` (the result of merging `theirs` into `ours`) so every
+/// locally modified path with `merge=ours` keeps the local content.
+/// Returns the paths that were resolved this way.
+pub fn resolve_ours(repo: &Repository, index: &mut Index, ours: &Commit, theirs: &Commit) -> Result<Vec<PathBuf>, Error> {
+    let base = repo.find_commit(repo.merge_base(ours.id(), theirs.id())?)?;
+    let base_tree = base.tree()?;
+    let our_tree = ours.tree()?;
+
+    let diff = repo.diff_tree_to_tree(Some(&base_tree), Some(&our_tree), None)?;
+    let changed: Vec<PathBuf> = diff.deltas()
+        .filter_map(|delta| delta.new_file().path().or_else(|| delta.old_file().path()))
+        .map(Path::to_path_buf)
+        .collect();
+
+    let mut resolved = Vec::new();
+    for path in changed {
+        let attr = repo.get_attr(&path, MERGE_ATTR, AttrCheckFlags::FILE_THEN_INDEX)?;
+        if attr != Some("ours") {
+            continue;
+        }
+
+        // Drops the merged entry along with any conflict stages
+        index.remove_path(&path)?;
+        if let Ok(entry) = our_tree.get_path(&path) {
+            index.add(&index_entry(&path, entry.filemode() as u32, entry.id()))?;
+        }
+        resolved.push(path);
+    }
+    Ok(resolved)
+}
+
+fn index_entry(path: &Path, mode: u32, id: git2::Oid) -> IndexEntry {
+    let path = path.to_string_lossy().replace('\\', "/").into_bytes();
+    IndexEntry {
+        ctime: IndexTime::new(0, 0),
+        mtime: IndexTime::new(0, 0),
+        dev: 0,
+        ino: 0,
+        mode,
+        uid: 0,
+        gid: 0,
+        file_size: 0,
+        id,
+        flags: path.len().min(0xfff) as u16,
+        flags_extended: 0,
+        path,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::{commit_file, setup_repos, test_keys};
+    use crate::watcher::Applied;
+    use crate::{fetch_commit, pull_repo};
+    use git2::Repository;
+    use std::fs;
+
+    #[test]
+    fn test_merge_ours_attribute_keeps_local_version() {
+        let repos = setup_repos("merge-ours");
+        let upstream = Repository::open(&repos.upstream).unwrap();
+        commit_file(&upstream, ".gitattributes", "generated.txt merge=ours\n", "Add attributes");
+        let base = commit_file(&upstream, "generated.txt", "v1\n", "Add generated file");
+        fetch_commit(&repos.local, &mut test_keys().fetch_options(), "origin", "master", base).unwrap();
+        pull_repo(&repos.local, base, &[]).unwrap();
+
+        commit_file(&upstream, "generated.txt", "upstream v2\n", "Regenerate upstream");
+        let latest_sha = commit_file(&upstream, "other.txt", "other\n", "Other change");
+        let local = Repository::open(&repos.local).unwrap();
+        commit_file(&local, "generated.txt", "local v2\n", "Regenerate locally");
+        fetch_commit(&repos.local, &mut test_keys().fetch_options(), "origin", "master", latest_sha).unwrap();
+
+        assert_eq!(Applied::MergePending, pull_repo(&repos.local, latest_sha, &[]).unwrap());
+
+        assert_eq!("local v2\n", fs::read_to_string(repos.local.join("generated.txt")).unwrap());
+        assert_eq!("other\n", fs::read_to_string(repos.local.join("other.txt")).unwrap());
+        // Everything but the merge=ours path is staged for the merge commit
+        let staged: Vec<String> = local.statuses(None).unwrap().iter().filter_map(|s| s.path().map(str::to_string)).collect();
+        assert_eq!(vec!["other.txt".to_string()], staged);
+    }
+}
@@ -0,0 +1,96 @@
+//! `--preserve-mtimes`: keep the modification times of files a pull did
+//! not change, so mtime based build tools don't rebuild them.
+
+use git2::{Error, FileMode, Oid, Repository, Tree, TreeWalkMode, TreeWalkResult};
+use std::fs::{self, File};
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+/// A tracked file's content and its mtime before the checkout.
+pub struct Saved {
+    path: PathBuf,
+    blob: Oid,
+    modified: SystemTime,
+}
+
+/// Records the mtimes of regular files whose blob and mode are the same in
+/// `old` and `new`. Files missing from the working tree are skipped.
+pub fn snapshot(repo: &Repository, old: &Tree, new: &Tree) -> Result<Vec<Saved>, Error> {
+    let workdir = match repo.workdir() {
+        Some(workdir) => workdir,
+        None => return Ok(Vec::new()),
+    };
+
+    let mut unchanged = Vec::new();
+    new.walk(TreeWalkMode::PreOrder, |root, entry| {
+        let mode = entry.filemode();
+        if mode != i32::from(FileMode::Blob) && mode != i32::from(FileMode::BlobExecutable) {
+            return TreeWalkResult::Ok;
+        }
+        let path = PathBuf::from(format!("{}{}", root, entry.name().unwrap_or_default()));
+        if old.get_path(&path).is_ok_and(|old| old.id() == entry.id() && old.filemode() == mode) {
+            unchanged.push((path, entry.id()));
+        }
+        TreeWalkResult::Ok
+    })?;
+
+    Ok(unchanged.into_iter()
+        .filter_map(|(path, blob)| {
+            let modified = fs::symlink_metadata(workdir.join(&path)).and_then(|meta| meta.modified()).ok()?;
+            Some(Saved { path, blob, modified })
+        })
+        .collect())
+}
+
+/// Puts the saved mtimes back on files that still hold the same blob in
+/// `tree`, the tree now checked out. Returns how many were preserved.
+pub fn restore(repo: &Repository, tree: &Tree, saved: &[Saved]) -> usize {
+    let workdir = match repo.workdir() {
+        Some(workdir) => workdir,
+        None => return 0,
+    };
+
+    saved.iter()
+        .filter(|file| tree.get_path(&file.path).is_ok_and(|entry| entry.id() == file.blob))
+        .filter(|file| {
+            let full_path = workdir.join(&file.path);
+            match fs::symlink_metadata(&full_path).and_then(|meta| meta.modified()) {
+                Ok(modified) if modified == file.modified => true,
+                Ok(_) => File::options().write(true).open(&full_path)
+                    .and_then(|f| f.set_modified(file.modified))
+                    .is_ok(),
+                Err(_) => false,
+            }
+        })
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{commit_file, setup_repos};
+    use std::time::Duration;
+
+    #[test]
+    fn test_restore_unchanged_files() {
+        let repos = setup_repos("mtimes");
+        let local = Repository::open(&repos.local).unwrap();
+        let old = local.head().unwrap().peel_to_tree().unwrap();
+        let kept = repos.local.join("kept.txt");
+        commit_file(&local, "kept.txt", "kept\n", "Add kept.txt");
+        let with_kept = local.head().unwrap().peel_to_tree().unwrap();
+        commit_file(&local, "README.md", "changed\n", "Change README");
+        let new = local.head().unwrap().peel_to_tree().unwrap();
+        let old_time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        File::options().write(true).open(&kept).unwrap().set_modified(old_time).unwrap();
+
+        let saved = snapshot(&local, &with_kept, &new).unwrap();
+        assert_eq!(vec![PathBuf::from("kept.txt")], saved.iter().map(|file| file.path.clone()).collect::<Vec<_>>());
+        assert!(snapshot(&local, &old, &new).unwrap().is_empty());
+
+        // What a checkout rewriting the file would do
+        File::options().write(true).open(&kept).unwrap().set_modified(SystemTime::now()).unwrap();
+        assert_eq!(1, restore(&local, &new, &saved));
+        assert_eq!(old_time, fs::metadata(&kept).unwrap().modified().unwrap());
+    }
+}
@@ -0,0 +1,294 @@
+//! The result of a single watch run.
+
+use anyhow::{anyhow, bail};
+use git2::{ErrorClass, ErrorCode, Oid};
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::history::CommitSummary;
+use crate::hooks::{self, HookRun};
+use crate::remotes::RemoteFetch;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Outcome {
+    /// The remote tip already matches HEAD. Reached without fetching any
+    /// objects or running merge analysis.
+    UpToDate { sha: Oid },
+    /// The remote tip `remote_sha` differs from HEAD `sha`, but after
+    /// fetching there was nothing to check out, see `NoChangeReason`.
+    NoChange { sha: Oid, remote_sha: Oid, reason: NoChangeReason },
+    /// A change was found but left alone for now, see `DeferReason`.
+    Deferred { sha: Oid, until: String, reason: DeferReason },
+    /// HEAD has `ahead` commits the remote tip `sha` lacks, so it cannot be
+    /// fast-forwarded. Only reported with `--warn-local-ahead`.
+    LocalAhead { sha: Oid, ahead: usize },
+    /// A change was found but not pulled, and will be offered again next run.
+    Skipped { sha: Oid, reason: SkipReason },
+    /// `--dry-run` found `commits` new commits and would bring HEAD from
+    /// `from` to `to`, see `PullMethod`.
+    WouldPull { from: Oid, to: Oid, commits: usize, method: PullMethod },
+    /// HEAD now includes `to`. `from` is unknown after a re-clone.
+    /// `commits` is only filled in for `--history-depth-report`.
+    Pulled { from: Option<Oid>, to: Oid, commits: Vec<CommitSummary> },
+    /// HEAD `from` and `to` diverged, so `to` was merged into the index and
+    /// working tree and left for someone to commit. HEAD has not moved.
+    MergePending { from: Oid, to: Oid, commits: Vec<CommitSummary> },
+}
+
+impl Outcome {
+    /// A stable identifier for monitoring, e.g. `up_to_date`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Outcome::UpToDate { .. } => "up_to_date",
+            Outcome::NoChange { .. } => "no_change",
+            Outcome::Deferred { .. } => "deferred",
+            Outcome::Skipped { .. } => "skipped",
+            Outcome::LocalAhead { .. } => "local_ahead",
+            Outcome::WouldPull { .. } => "would_pull",
+            Outcome::Pulled { .. } => "pulled",
+            Outcome::MergePending { .. } => "merge_pending",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeferReason {
+    /// Outside every `--defer-until` window.
+    DeployWindow,
+    /// The remote tip is younger than `--min-commit-age`.
+    CommitAge,
+}
+
+impl DeferReason {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            DeferReason::DeployWindow => "deploy_window",
+            DeferReason::CommitAge => "commit_age",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NoChangeReason {
+    /// HEAD already contains the remote tip, e.g. after local commits.
+    AlreadyContained,
+    /// Nothing under `--subtree-prefix` changed.
+    SubtreeUnchanged,
+}
+
+impl NoChangeReason {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            NoChangeReason::AlreadyContained => "already_contained",
+            NoChangeReason::SubtreeUnchanged => "subtree_unchanged",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PullMethod {
+    FastForward,
+    /// HEAD has diverged; the merge would be left to commit.
+    Merge,
+    /// `--gerrit-change`: the patchset replaces HEAD on a detached checkout.
+    Checkout,
+}
+
+impl PullMethod {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            PullMethod::FastForward => "fast_forward",
+            PullMethod::Merge => "merge",
+            PullMethod::Checkout => "checkout",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SkipReason {
+    /// The `--pull-confirmation` prompt was answered no.
+    Declined,
+    /// `--pull-confirmation` had no terminal and `--non-interactive skip`.
+    NotInteractive,
+}
+
+impl SkipReason {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            SkipReason::Declined => "declined",
+            SkipReason::NotInteractive => "not_interactive",
+        }
+    }
+}
+
+/// Everything the core logic reports about one repository; rendered by an
+/// `OutcomeFormatter` and turned into the process exit code.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RunOutcome {
+    pub repo: String,
+    /// What was done, or `None` when the run failed.
+    pub outcome: Option<Outcome>,
+    /// HEAD before and after the run.
+    pub old_sha: Option<Oid>,
+    pub new_sha: Option<Oid>,
+    /// The remote tip the run compared against.
+    pub remote_sha: Option<Oid>,
+    /// Commits on the remote tip missing from the old HEAD, known once the
+    /// new objects were fetched.
+    pub behind: Option<usize>,
+    /// Set when the run never touched the network.
+    pub offline: bool,
+    /// Per-remote results of `--fetch-all-remotes`.
+    pub remote_fetches: Vec<RemoteFetch>,
+    pub duration: Duration,
+    /// Every hook that ran, in order.
+    pub hooks: Vec<HookRun>,
+    /// Set for `--dry-run`, where `planned_hooks` lists the hooks that would
+    /// have run instead of running them.
+    pub dry_run: bool,
+    pub planned_hooks: Vec<&'static str>,
+    /// For `--dry-run`, the guards that would stop the real run before it
+    /// pulls, such as a `--pull-confirmation` prompt.
+    pub planned_guards: Vec<&'static str>,
+    pub error: Option<RunError>,
+}
+
+/// `--exit-code OUTCOME=CODE`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExitCodeRule {
+    pub key: String,
+    pub code: u8,
+}
+
+/// Everything an `--exit-code` rule can match.
+const EXIT_CODE_KEYS: &[&str] = &[
+    "up_to_date", "no_change", "deferred", "skipped", "local_ahead", "would_pull", "pulled", "merge_pending",
+    "error", "error_auth", "error_conflict", "error_network", "error_corruption", "error_hook", "error_other",
+];
+
+/// Other names accepted for `EXIT_CODE_KEYS`; `-` may stand in for `_`.
+const EXIT_CODE_ALIASES: &[(&str, &str)] = &[
+    ("diverged", "local_ahead"),
+    ("error_net", "error_network"),
+    ("conflict", "error_conflict"),
+];
+
+impl FromStr for ExitCodeRule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (key, code) = s.split_once('=')
+            .ok_or_else(|| anyhow!("expected OUTCOME=CODE, got '{}'", s))?;
+        let name = key.trim().replace('-', "_");
+        let key = EXIT_CODE_ALIASES.iter()
+            .find(|(alias, _)| *alias == name)
+            .map_or(name.as_str(), |(_, key)| key);
+        if !EXIT_CODE_KEYS.contains(&key) {
+            bail!("unknown outcome '{}', expected one of {}", name, EXIT_CODE_KEYS.join(", "));
+        }
+        let code = code.trim().parse::<u8>()
+            .map_err(|_| anyhow!("exit code '{}' is not between 0 and 255", code.trim()))?;
+        Ok(ExitCodeRule { key: key.to_string(), code })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RunError {
+    /// One of the `hooks::error_category` categories.
+    pub category: &'static str,
+    pub message: String,
+    /// The libgit2 error behind the failure, if there was one.
+    pub git: Option<GitErrorCode>,
+}
+
+/// The code and class of a libgit2 error, kept as reported so monitoring
+/// can match on specific conditions.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GitErrorCode {
+    pub code: ErrorCode,
+    pub class: ErrorClass,
+    pub raw_code: i32,
+    pub raw_class: u32,
+}
+
+impl RunError {
+    pub fn new(category: &'static str, message: String) -> RunError {
+        RunError { category, message, git: None }
+    }
+
+    pub fn from_error(err: &anyhow::Error) -> RunError {
+        let git = err.chain()
+            .find_map(|cause| cause.downcast_ref::<git2::Error>())
+            .map(|e| GitErrorCode { code: e.code(), class: e.class(), raw_code: e.raw_code(), raw_class: e.raw_class() });
+        RunError { category: hooks::error_category(err), message: format!("{:#}", err), git }
+    }
+}
+
+impl RunOutcome {
+    pub fn new(repo: &str) -> RunOutcome {
+        RunOutcome {
+            repo: repo.to_string(),
+            outcome: None,
+            old_sha: None,
+            new_sha: None,
+            remote_sha: None,
+            behind: None,
+            offline: false,
+            remote_fetches: Vec::new(),
+            duration: Duration::ZERO,
+            hooks: Vec::new(),
+            dry_run: false,
+            planned_hooks: Vec::new(),
+            planned_guards: Vec::new(),
+            error: None,
+        }
+    }
+
+    /// The process exit code: the last matching rule for the outcome kind or
+    /// `error_<category>`, then one for `error`. Without a rule, errors
+    /// exit with 1 and everything else with 0.
+    pub fn exit_code(&self, rules: &[ExitCodeRule]) -> i32 {
+        let keys = match (&self.error, &self.outcome) {
+            (Some(error), _) => vec![format!("error_{}", error.category), "error".to_string()],
+            (None, Some(outcome)) => vec![outcome.kind().to_string()],
+            (None, None) => vec!["error".to_string()],
+        };
+        let default = if self.error.is_some() { 1 } else { 0 };
+        keys.iter()
+            .find_map(|key| rules.iter().rev().find(|rule| &rule.key == key))
+            .map_or(default, |rule| i32::from(rule.code))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exit_code() {
+        let rules: Vec<ExitCodeRule> = ["pulled=10", "error=2", "error_auth=77", "pulled=11"]
+            .iter().map(|rule| rule.parse().unwrap()).collect();
+        let sha = Oid::zero();
+        let with = |outcome, error| RunOutcome { outcome, error, ..RunOutcome::new("api") };
+
+        assert_eq!(11, with(Some(Outcome::Pulled { from: None, to: sha, commits: Vec::new() }), None).exit_code(&rules));
+        assert_eq!(0, with(Some(Outcome::UpToDate { sha }), None).exit_code(&rules));
+        assert_eq!(77, with(None, Some(RunError::new("auth", String::new()))).exit_code(&rules));
+        assert_eq!(2, with(None, Some(RunError::new("network", String::new()))).exit_code(&rules));
+        assert_eq!(1, with(None, Some(RunError::new("network", String::new()))).exit_code(&[]));
+    }
+
+    #[test]
+    fn test_parse_exit_code_rule() {
+        assert_eq!(ExitCodeRule { key: "local_ahead".to_string(), code: 3 }, "local_ahead=3".parse().unwrap());
+        assert!("pulled=256".parse::<ExitCodeRule>().is_err());
+        assert!("pulled=-1".parse::<ExitCodeRule>().is_err());
+        assert_eq!(ExitCodeRule { key: "local_ahead".to_string(), code: 3 }, "diverged=3".parse().unwrap());
+        assert_eq!(ExitCodeRule { key: "up_to_date".to_string(), code: 0 }, "up-to-date=0".parse().unwrap());
+        assert_eq!(ExitCodeRule { key: "error_auth".to_string(), code: 77 }, "error-auth=77".parse().unwrap());
+        assert_eq!(ExitCodeRule { key: "error_network".to_string(), code: 75 }, "error-net=75".parse().unwrap());
+        assert_eq!(ExitCodeRule { key: "error_conflict".to_string(), code: 4 }, "conflict=4".parse().unwrap());
+        assert!("sideways=3".parse::<ExitCodeRule>().is_err());
+        assert!("pulled".parse::<ExitCodeRule>().is_err());
+    }
+}
@@ -0,0 +1,70 @@
+//! Output formats shared by the commands.
+
+use clap::ValueEnum;
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum OutputFormat {
+    Human,
+    Json,
+}
+
+/// Quotes `s` as a JSON string.
+pub fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// A JSON string, or `null` when there is no value.
+pub fn json_opt(s: Option<&str>) -> String {
+    s.map(json_string).unwrap_or_else(|| "null".to_string())
+}
+
+/// Formats seconds since the epoch as `YYYY-MM-DD HH:MM` in UTC.
+pub fn format_timestamp(secs: i64) -> String {
+    let days = secs.div_euclid(86_400);
+    let minutes = secs.rem_euclid(86_400) / 60;
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02} {:02}:{:02}", year, month, day, minutes / 60, minutes % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_string() {
+        assert_eq!(r#""plain""#, json_string("plain"));
+        assert_eq!(r#""a \"b\"\\c\nd\u0001""#, json_string("a \"b\"\\c\nd\u{1}"));
+        assert_eq!("null", json_opt(None));
+    }
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!("1970-01-01 00:00", format_timestamp(0));
+        assert_eq!("2024-02-29 13:05", format_timestamp(1_709_211_900));
+    }
+}
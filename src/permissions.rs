@@ -0,0 +1,144 @@
+//! Fixing up modes and ownership of files touched by a pull.
+
+use anyhow::{anyhow, Context, Result};
+use git2::{Oid, Repository};
+
+use crate::history::changed_files;
+
+/// `--checkout-owner` value: numeric user and group ids.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Owner {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl std::str::FromStr for Owner {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (uid, gid) = s.split_once(':').ok_or_else(|| anyhow!("expected UID:GID, got '{}'", s))?;
+        Ok(Owner {
+            uid: uid.parse().map_err(|_| anyhow!("invalid uid '{}'", uid))?,
+            gid: gid.parse().map_err(|_| anyhow!("invalid gid '{}'", gid))?,
+        })
+    }
+}
+
+/// Parses an octal mode such as `0640`.
+pub fn parse_mode(s: &str) -> Result<u32> {
+    let mode = u32::from_str_radix(s.trim_start_matches("0o"), 8)
+        .map_err(|_| anyhow!("'{}' is not an octal mode", s))?;
+    if mode > 0o7777 {
+        return Err(anyhow!("mode '{}' is out of range", s));
+    }
+    Ok(mode)
+}
+
+/// Applies `mode` and, when running as root, `owner` to every file the pull
+/// from `from` to `to` wrote. Returns how many files were updated.
+///
+/// Symlinks, and paths that resolve outside the working tree, are skipped,
+/// so a committed link can't redirect a root run to files elsewhere.
+#[cfg(unix)]
+pub fn apply(repo: &Repository, repo_name: &str, from: Oid, to: Oid, mode: Option<u32>, owner: Option<Owner>) -> Result<usize> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let workdir = repo.workdir().ok_or_else(|| anyhow!("Repository has no working tree"))?;
+    let workdir = workdir.canonicalize()
+        .with_context(|| format!("Failed to resolve {}", workdir.display()))?;
+    // SAFETY: geteuid has no preconditions
+    let owner = match owner {
+        Some(_) if unsafe { libc::geteuid() } != 0 => {
+            eprintln!("[{}] Not running as root; ignoring --checkout-owner", repo_name);
+            None
+        }
+        owner => owner,
+    };
+
+    let mut updated = 0;
+    for path in changed_files(repo, from, to)? {
+        let file = workdir.join(&path);
+        // Sparse checkouts leave some paths off disk
+        match std::fs::symlink_metadata(&file) {
+            Ok(meta) if meta.file_type().is_file() => {}
+            _ => continue,
+        }
+        // A symlinked parent directory could still point elsewhere
+        if !file.canonicalize().is_ok_and(|real| real.starts_with(&workdir)) {
+            eprintln!("[{}] Skipping {}, which resolves outside the working tree", repo_name, path.display());
+            continue;
+        }
+        if let Some(mode) = mode {
+            std::fs::set_permissions(&file, std::fs::Permissions::from_mode(mode))
+                .with_context(|| format!("Failed to set the mode of {}", file.display()))?;
+        }
+        if let Some(owner) = owner {
+            std::os::unix::fs::lchown(&file, Some(owner.uid), Some(owner.gid))
+                .with_context(|| format!("Failed to change the owner of {}", file.display()))?;
+        }
+        updated += 1;
+    }
+    Ok(updated)
+}
+
+#[cfg(not(unix))]
+pub fn apply(_repo: &Repository, _repo_name: &str, _from: Oid, _to: Oid, _mode: Option<u32>, _owner: Option<Owner>) -> Result<usize> {
+    Err(anyhow!("Checkout permissions are only supported on Unix"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{commit_file, setup_repos};
+    use clap::Parser;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(0o640, parse_mode("0640").unwrap());
+        assert!(parse_mode("0999").is_err());
+        assert_eq!(Owner { uid: 33, gid: 33 }, "33:33".parse().unwrap());
+        assert!("www-data".parse::<Owner>().is_err());
+        let args = crate::Cli::try_parse_from(["repo-watcher", "--checkout-dir-permissions", "0640"]).unwrap();
+        assert_eq!(Some(0o640), args.checkout_file_mode);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_apply_only_touches_changed_files() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let repos = setup_repos("permissions");
+        let repo = Repository::open(&repos.upstream).unwrap();
+        let from = repo.head().unwrap().target().unwrap();
+        let to = commit_file(&repo, "new.txt", "new\n", "Add file");
+
+        assert_eq!(1, apply(&repo, "test", from, to, Some(0o600), None).unwrap());
+
+        let mode = |file: &str| std::fs::metadata(repos.upstream.join(file)).unwrap().permissions().mode() & 0o777;
+        assert_eq!(0o600, mode("new.txt"));
+        assert_ne!(0o600, mode("README.md"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_apply_skips_symlinks() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let repos = setup_repos("permissions-symlink");
+        let outside = repos.root.join("outside.txt");
+        std::fs::write(&outside, "outside\n").unwrap();
+        std::fs::set_permissions(&outside, std::fs::Permissions::from_mode(0o644)).unwrap();
+        let repo = Repository::open(&repos.upstream).unwrap();
+        let from = repo.head().unwrap().target().unwrap();
+        std::os::unix::fs::symlink(&outside, repos.upstream.join("link")).unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(std::path::Path::new("link")).unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let sig = git2::Signature::now("test", "test@example.com").unwrap();
+        let parent = repo.find_commit(from).unwrap();
+        let to = repo.commit(Some("HEAD"), &sig, &sig, "Add link", &tree, &[&parent]).unwrap();
+
+        assert_eq!(0, apply(&repo, "test", from, to, Some(0o600), None).unwrap());
+        assert_eq!(0o644, std::fs::metadata(&outside).unwrap().permissions().mode() & 0o777);
+    }
+}
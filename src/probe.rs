@@ -0,0 +1,87 @@
+//! `probe`: a network diagnostic that times the ref advertisement without
+//! fetching any objects.
+
+use anyhow::{anyhow, Context, Result};
+use git2::{Direction, Oid, Repository};
+use std::time::{Duration, Instant};
+
+use crate::output::{json_opt, json_string, OutputFormat};
+use crate::Settings;
+
+pub struct ProbeReport {
+    pub remote: String,
+    pub url: String,
+    /// Time to connect and receive the ref advertisement.
+    pub latency: Duration,
+    pub ref_count: usize,
+    pub branch: String,
+    pub branch_sha: Option<Oid>,
+}
+
+pub fn probe(settings: &Settings) -> Result<ProbeReport> {
+    let repo = Repository::open(&settings.local_path)?;
+    let mut remote = repo.find_remote(&settings.remote)?;
+    let url = remote.url()
+        .ok_or_else(|| anyhow!("Remote '{}' has no URL", settings.remote))?
+        .to_string();
+
+    let started = Instant::now();
+    let connection = remote.connect_auth(Direction::Fetch, Some(settings.ssh_keys.callbacks()), None)
+        .with_context(|| format!("Failed to connect to {}", url))?;
+    let heads = connection.list()?;
+    let latency = started.elapsed();
+
+    let refname = format!("refs/heads/{}", settings.branch);
+    Ok(ProbeReport {
+        remote: settings.remote.clone(),
+        url,
+        latency,
+        ref_count: heads.len(),
+        branch: settings.branch.clone(),
+        branch_sha: heads.iter().find(|head| head.name() == refname).map(|head| head.oid()),
+    })
+}
+
+impl ProbeReport {
+    pub fn render(&self, format: OutputFormat) -> String {
+        let sha = self.branch_sha.map(|sha| sha.to_string());
+        match format {
+            OutputFormat::Human => format!(
+                "{} ({}): {} ms, {} refs advertised, {} at {}",
+                self.remote,
+                self.url,
+                self.latency.as_millis(),
+                self.ref_count,
+                self.branch,
+                sha.as_deref().unwrap_or("(not advertised)"),
+            ),
+            OutputFormat::Json => format!(
+                "{{\"remote\":{},\"url\":{},\"latency_ms\":{},\"ref_count\":{},\"branch\":{},\"branch_sha\":{}}}",
+                json_string(&self.remote),
+                json_string(&self.url),
+                self.latency.as_millis(),
+                self.ref_count,
+                json_string(&self.branch),
+                json_opt(sha.as_deref()),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{settings_for, setup_repos};
+
+    #[test]
+    fn test_probe() {
+        let repos = setup_repos("probe");
+        let expected_sha = Repository::open(&repos.upstream).unwrap().head().unwrap().target();
+
+        let report = probe(&settings_for(&repos)).unwrap();
+
+        assert_eq!(expected_sha, report.branch_sha);
+        assert!(report.ref_count >= 2);
+        assert!(report.render(OutputFormat::Json).contains("\"branch\":\"master\""));
+    }
+}
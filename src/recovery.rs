@@ -0,0 +1,133 @@
+//! Detection of and recovery from a corrupted object database.
+
+use anyhow::{anyhow, Context, Result};
+use git2::build::RepoBuilder;
+use git2::{ErrorClass, ErrorCode, Repository};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::credentials::SshKeys;
+use crate::Settings;
+
+/// Returns the libgit2 error in `err`'s chain if it points at a damaged
+/// object store (bad packfile, unreadable loose object, broken index).
+///
+/// A missing object is also reported with the `Odb` class, but usually just
+/// means the object was never fetched, so it doesn't count.
+pub fn find_corruption(err: &anyhow::Error) -> Option<&git2::Error> {
+    err.chain()
+        .filter_map(|cause| cause.downcast_ref::<git2::Error>())
+        .filter(|e| e.code() != ErrorCode::NotFound && !e.message().starts_with("object not found"))
+        .find(|e| matches!(e.class(), ErrorClass::Odb | ErrorClass::Zlib | ErrorClass::Indexer))
+}
+
+/// Runs `git fsck` against the repository and, when `reclone` is set,
+/// replaces it with a fresh clone of the watched remote and branch.
+///
+/// Without `reclone` the original error is returned after the report.
+pub fn recover(settings: &Settings, reclone: bool, err: anyhow::Error) -> Result<()> {
+    let local_path = settings.local_path.as_path();
+    eprintln!("!!! [{}] Object database corruption detected in {}", settings.name, settings.display_path.display());
+    eprintln!("!!! {:#}", err);
+
+    match run_fsck(local_path) {
+        Ok(report) => eprintln!("git fsck:\n{}", report),
+        Err(fsck_err) => eprintln!("git fsck could not be run: {:#}", fsck_err),
+    }
+
+    if !reclone {
+        eprintln!("!!! Pass --auto-reclone-on-corruption to replace the repository with a fresh clone");
+        return Err(err);
+    }
+
+    let backup = reclone_repo(local_path, &settings.ssh_keys, &settings.remote, &settings.branch)
+        .context("Automatic re-clone failed")?;
+    eprintln!("!!! [{}] Repository re-cloned; the corrupted copy was moved to {}", settings.name, backup.display());
+    Ok(())
+}
+
+fn run_fsck(local_path: &Path) -> Result<String> {
+    let output = Command::new("git")
+        .arg("fsck")
+        .arg("--full")
+        .arg("--no-progress")
+        .current_dir(local_path)
+        .output()?;
+
+    let mut report = String::from_utf8_lossy(&output.stdout).into_owned();
+    report.push_str(&String::from_utf8_lossy(&output.stderr));
+    if output.status.success() && report.trim().is_empty() {
+        report.push_str("no problems found");
+    }
+    Ok(report)
+}
+
+/// Clones into a sibling directory, then swaps it into place. Returns the
+/// path the corrupted repository was moved to.
+fn reclone_repo(local_path: &Path, ssh_keys: &SshKeys, remote: &str, branch: &str) -> Result<PathBuf> {
+    let url = {
+        let repo = Repository::open(local_path)?;
+        let remote = repo.find_remote(remote)?;
+        remote.url()
+            .ok_or_else(|| anyhow!("Remote '{}' has no URL", remote.name().unwrap_or_default()))?
+            .to_string()
+    };
+
+    let file_name = local_path.file_name()
+        .ok_or_else(|| anyhow!("Cannot re-clone into {}", local_path.display()))?
+        .to_string_lossy()
+        .into_owned();
+    let stamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let fresh = local_path.with_file_name(format!("{}.reclone-{}", file_name, stamp));
+    let backup = local_path.with_file_name(format!("{}.corrupt-{}", file_name, stamp));
+
+    eprintln!("!!! Re-cloning {} into {}", url, fresh.display());
+    let repo = RepoBuilder::new()
+        .branch(branch)
+        .fetch_options(ssh_keys.fetch_options())
+        .clone(&url, &fresh)
+        .with_context(|| format!("Failed to clone {}", url))?;
+    // Keep the remote name the rest of the configuration refers to
+    if remote != "origin" {
+        repo.remote_rename("origin", remote)?;
+    }
+
+    fs::rename(local_path, &backup)
+        .with_context(|| format!("Failed to move {} aside", local_path.display()))?;
+    fs::rename(&fresh, local_path)
+        .with_context(|| format!("Failed to move {} into place", fresh.display()))?;
+    Ok(backup)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{setup_repos, test_keys};
+
+    #[test]
+    fn test_find_corruption() {
+        let corrupt = anyhow::Error::new(git2::Error::new(
+            git2::ErrorCode::GenericError, ErrorClass::Odb, "packfile is truncated"));
+        let network = anyhow::Error::new(git2::Error::new(
+            git2::ErrorCode::GenericError, ErrorClass::Net, "connection refused"));
+
+        assert!(find_corruption(&corrupt.context("Failed to fetch")).is_some());
+        assert!(find_corruption(&network).is_none());
+
+        let missing = anyhow::Error::new(git2::Error::new(
+            git2::ErrorCode::NotFound, ErrorClass::Odb, "object not found - no match for id (1234)"));
+        assert!(find_corruption(&missing).is_none());
+    }
+
+    #[test]
+    fn test_reclone_repo() {
+        let repos = setup_repos("reclone");
+
+        let backup = reclone_repo(&repos.local, &test_keys(), "origin", "master").unwrap();
+
+        assert!(backup.exists());
+        assert!(Repository::open(&repos.local).unwrap().head().is_ok());
+    }
+}
@@ -0,0 +1,80 @@
+//! Fetching every configured remote, not just the watched one.
+
+use git2::{Error, Repository};
+
+use crate::credentials::SshKeys;
+use crate::output::{json_opt, json_string};
+use crate::trace;
+
+/// How fetching one remote went. A failed remote does not stop the others.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RemoteFetch {
+    pub name: String,
+    pub received_objects: usize,
+    pub error: Option<String>,
+}
+
+impl RemoteFetch {
+    pub fn to_human(&self) -> String {
+        match &self.error {
+            Some(error) => format!("fetch of {} failed: {}", self.name, error),
+            None => format!("fetched {} ({} objects)", self.name, self.received_objects),
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"name\":{},\"received_objects\":{},\"error\":{}}}",
+            json_string(&self.name),
+            self.received_objects,
+            json_opt(self.error.as_deref()),
+        )
+    }
+}
+
+/// Fetches each remote of `repo` with its configured refspecs, updating
+/// its remote-tracking refs.
+pub fn fetch_all(repo: &Repository, ssh_keys: &SshKeys) -> Result<Vec<RemoteFetch>, Error> {
+    let mut fetches = Vec::new();
+    for name in repo.remotes()?.iter().flatten() {
+        let result = repo.find_remote(name).and_then(|mut remote| {
+            let mut fetch_options = ssh_keys.fetch_options();
+            remote.fetch(&[] as &[&str], Some(&mut fetch_options), None)?;
+            crate::trace_fetch(&remote, "configured");
+            Ok(remote.stats().received_objects())
+        });
+        if let Err(e) = &result {
+            trace::event("fetch", &[("remote", trace::text(name)), ("error", trace::text(e.message()))]);
+        }
+        fetches.push(match result {
+            Ok(received_objects) => RemoteFetch { name: name.to_string(), received_objects, error: None },
+            Err(e) => RemoteFetch { name: name.to_string(), received_objects: 0, error: Some(e.message().to_string()) },
+        });
+    }
+    Ok(fetches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{commit_file, setup_repos, test_keys};
+
+    #[test]
+    fn test_fetch_all() {
+        let repos = setup_repos("fetch-all");
+        let local = Repository::open(&repos.local).unwrap();
+        local.remote("mirror", repos.upstream.to_str().unwrap()).unwrap();
+        local.remote("broken", repos.root.join("missing").to_str().unwrap()).unwrap();
+        let latest_sha = commit_file(&Repository::open(&repos.upstream).unwrap(), "README.md", "new\n", "Update");
+
+        let fetches = fetch_all(&local, &test_keys()).unwrap();
+
+        assert_eq!(3, fetches.len());
+        let broken = fetches.iter().find(|f| f.name == "broken").unwrap();
+        assert!(broken.error.is_some());
+        for name in ["origin", "mirror"] {
+            let tracking = local.refname_to_id(&format!("refs/remotes/{}/master", name)).unwrap();
+            assert_eq!(latest_sha, tracking);
+        }
+    }
+}
@@ -0,0 +1,173 @@
+//! `self-test`: the full detect and pull cycle against throwaway local
+//! repositories, to check that an installation works without touching a
+//! real remote or credentials.
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::Parser;
+use git2::build::RepoBuilder;
+use git2::{Commit, Oid, Repository, RepositoryInitOptions, Signature};
+use std::cell::Cell;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use crate::credentials::SshKeys;
+use crate::outcome::{Outcome, RunOutcome};
+use crate::output::{json_opt, json_string, OutputFormat};
+use crate::watcher::Watcher;
+use crate::{update, Cli, Settings};
+
+const FILE: &str = "self-test.txt";
+
+pub struct Step {
+    pub name: &'static str,
+    pub duration: Duration,
+    pub error: Option<String>,
+}
+
+pub struct SelfTestReport {
+    /// The steps that ran; a failure stops the run.
+    pub steps: Vec<Step>,
+    pub total: usize,
+}
+
+type StepFn<'a> = Box<dyn FnMut() -> Result<()> + 'a>;
+
+/// Removes the scratch directory however the test ends.
+struct Scratch(PathBuf);
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        fs::remove_dir_all(&self.0).ok();
+    }
+}
+
+/// Runs every step in order, stopping at the first failure.
+pub fn self_test() -> SelfTestReport {
+    let scratch = Scratch(std::env::temp_dir().join(format!("repo-watcher-self-test-{}", std::process::id())));
+    let upstream_path = scratch.0.join("upstream.git");
+    let local_path = scratch.0.join("local");
+    let settings = Settings {
+        name: "self-test".to_string(),
+        local_path: local_path.clone(),
+        display_path: local_path.clone(),
+        remote: "origin".to_string(),
+        branch: "master".to_string(),
+        ssh_keys: SshKeys::new(Vec::new()),
+        disable_hooks: true,
+    };
+    let args = Cli::parse_from(["repo-watcher"]);
+    let latest = Cell::new(Oid::zero());
+
+    let mut steps: Vec<(&'static str, StepFn)> = vec![
+        ("create repositories", Box::new(|| {
+            fs::remove_dir_all(&scratch.0).ok();
+            let upstream = Repository::init_opts(&upstream_path, RepositoryInitOptions::new().bare(true).initial_head("master"))?;
+            commit(&upstream, "first\n", "Initial commit")?;
+            let url = format!("file://{}", upstream_path.display());
+            RepoBuilder::new().branch("master").clone(&url, &local_path)
+                .with_context(|| format!("Failed to clone {}", url))?;
+            Ok(())
+        })),
+        ("commit upstream", Box::new(|| {
+            latest.set(commit(&Repository::open(&upstream_path)?, "second\n", "Second commit")?);
+            Ok(())
+        })),
+        ("detect change", Box::new(|| {
+            let detection = Watcher::new(&settings).fetch()?;
+            if detection.remote_sha != latest.get() {
+                bail!("The remote advertised {} instead of {}", detection.remote_sha, latest.get());
+            }
+            if detection.behind != 1 {
+                bail!("The new commit was not detected");
+            }
+            Ok(())
+        })),
+        ("pull", Box::new(|| {
+            match update(&args, &settings, &mut RunOutcome::new(&settings.name))? {
+                Outcome::Pulled { to, .. } if to == latest.get() => Ok(()),
+                outcome => Err(anyhow!("Expected a pull of {}, got {:?}", latest.get(), outcome)),
+            }
+        })),
+        ("verify checkout", Box::new(|| {
+            let contents = fs::read_to_string(local_path.join(FILE))?;
+            if contents != "second\n" {
+                bail!("{} has unexpected contents {:?}", FILE, contents);
+            }
+            Ok(())
+        })),
+        ("detect no change", Box::new(|| {
+            match update(&args, &settings, &mut RunOutcome::new(&settings.name))? {
+                Outcome::UpToDate { .. } => Ok(()),
+                outcome => Err(anyhow!("Expected up to date, got {:?}", outcome)),
+            }
+        })),
+    ];
+
+    let mut report = SelfTestReport { steps: Vec::new(), total: steps.len() };
+    for (name, step) in steps.iter_mut() {
+        let started = Instant::now();
+        let error = step().err().map(|e| format!("{:#}", e));
+        let failed = error.is_some();
+        report.steps.push(Step { name, duration: started.elapsed(), error });
+        if failed {
+            break;
+        }
+    }
+    report
+}
+
+/// Commits `contents` as the only file of a new commit on HEAD.
+fn commit(repo: &Repository, contents: &str, message: &str) -> Result<Oid, git2::Error> {
+    let blob = repo.blob(contents.as_bytes())?;
+    let mut tree = repo.treebuilder(None)?;
+    tree.insert(FILE, blob, 0o100644)?;
+    let tree = repo.find_tree(tree.write()?)?;
+    let sig = Signature::now("repo-watcher", "repo-watcher@localhost")?;
+    let parent = repo.head().ok().and_then(|head| head.peel_to_commit().ok());
+    let parents: Vec<&Commit> = parent.iter().collect();
+    repo.commit(Some("HEAD"), &sig, &sig, message, &tree, &parents)
+}
+
+impl SelfTestReport {
+    /// Every step ran, and none failed.
+    pub fn passed(&self) -> bool {
+        self.steps.len() == self.total && self.steps.iter().all(|step| step.error.is_none())
+    }
+
+    pub fn render(&self, format: OutputFormat) -> String {
+        match format {
+            OutputFormat::Human => {
+                let mut out: Vec<String> = self.steps.iter().map(|step| match &step.error {
+                    None => format!("PASS {} ({} ms)", step.name, step.duration.as_millis()),
+                    Some(error) => format!("FAIL {}: {}", step.name, error),
+                }).collect();
+                out.push(format!("self-test {}", if self.passed() { "passed" } else { "failed" }));
+                out.join("\n")
+            }
+            OutputFormat::Json => {
+                let steps: Vec<String> = self.steps.iter().map(|step| format!(
+                    "{{\"name\":{},\"passed\":{},\"duration_ms\":{},\"error\":{}}}",
+                    json_string(step.name),
+                    step.error.is_none(),
+                    step.duration.as_millis(),
+                    json_opt(step.error.as_deref()),
+                )).collect();
+                format!("{{\"passed\":{},\"steps\":[{}]}}", self.passed(), steps.join(","))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_self_test() {
+        let report = self_test();
+
+        assert!(report.passed(), "{}", report.render(OutputFormat::Human));
+        assert!(!std::env::temp_dir().join(format!("repo-watcher-self-test-{}", std::process::id())).exists());
+    }
+}
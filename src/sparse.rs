@@ -0,0 +1,146 @@
+//! Sparse checkouts: only paths matching the `--sparse-path` patterns are
+//! written to the working tree.
+//!
+//! libgit2 has no sparse-checkout support of its own, so checkouts are
+//! limited with path filters and every other index entry is marked
+//! skip-worktree. The same patterns go to `info/sparse-checkout` so the git
+//! command line agrees about which files should be on disk.
+
+use git2::{Error, ErrorCode, Index, IndexEntryExtendedFlag, IndexEntryFlag, Oid, Pathspec, PathspecFlags, Repository, Status, Tree};
+use git2::build::CheckoutBuilder;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Checks out `tree`, materializing only the paths matching `patterns`.
+/// Returns how many unmodified files outside the patterns were removed.
+pub fn checkout(repo: &Repository, tree: &Tree, patterns: &[String]) -> Result<usize, Error> {
+    let pathspec = Pathspec::new(patterns.iter())?;
+    let workdir = repo.workdir()
+        .ok_or_else(|| Error::from_str("Sparse checkout needs a working tree"))?
+        .to_path_buf();
+
+    // Decide what to drop before the index changes under us; files with
+    // local modifications are left alone
+    let mut removable = Vec::new();
+    for entry in repo.index()?.iter() {
+        let path = PathBuf::from(String::from_utf8_lossy(&entry.path).into_owned());
+        if !pathspec.matches_path(&path, PathspecFlags::DEFAULT)
+            && repo.status_file(&path).map(|s| s == Status::CURRENT).unwrap_or(false) {
+            removable.push(path);
+        }
+    }
+
+    let mut checkout = CheckoutBuilder::new();
+    checkout.safe();
+    for pattern in patterns {
+        checkout.path(pattern);
+    }
+    repo.checkout_tree(tree.as_object(), Some(&mut checkout))?;
+
+    let mut index = repo.index()?;
+    index.read_tree(tree)?;
+    let entries: Vec<_> = index.iter().collect();
+    for mut entry in entries {
+        let path = PathBuf::from(String::from_utf8_lossy(&entry.path).into_owned());
+        if !pathspec.matches_path(&path, PathspecFlags::DEFAULT) {
+            entry.flags |= IndexEntryFlag::EXTENDED.bits();
+            entry.flags_extended |= IndexEntryExtendedFlag::SKIP_WORKTREE.bits();
+            index.add(&entry)?;
+        }
+    }
+    index.write()?;
+
+    let mut removed = 0;
+    for path in removable {
+        if fs::remove_file(workdir.join(&path)).is_ok() {
+            removed += 1;
+            remove_empty_parents(&workdir, &path);
+        }
+    }
+
+    write_config(repo, patterns)?;
+    Ok(removed)
+}
+
+/// True if `path` is marked skip-worktree in `index`, i.e. a sparse
+/// checkout left it off disk on purpose.
+pub fn is_skip_worktree(index: &Index, path: &Path) -> bool {
+    index.get_path(path, 0)
+        .is_some_and(|entry| IndexEntryExtendedFlag::from_bits_truncate(entry.flags_extended).is_skip_worktree())
+}
+
+/// The id of the tree at `prefix` in `commit`, or `None` if the commit has
+/// nothing there.
+pub fn subtree_id(repo: &Repository, commit: Oid, prefix: &Path) -> Result<Option<Oid>, Error> {
+    match repo.find_commit(commit)?.tree()?.get_path(prefix) {
+        Ok(entry) => Ok(Some(entry.id())),
+        Err(e) if e.code() == ErrorCode::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+pub fn remove_empty_parents(workdir: &Path, path: &Path) {
+    let mut dir = path.parent();
+    while let Some(parent) = dir.filter(|p| !p.as_os_str().is_empty()) {
+        if fs::remove_dir(workdir.join(parent)).is_err() {
+            break;
+        }
+        dir = parent.parent();
+    }
+}
+
+fn write_config(repo: &Repository, patterns: &[String]) -> Result<(), Error> {
+    let info = repo.path().join("info");
+    let mut contents = patterns.join("\n");
+    contents.push('\n');
+    fs::create_dir_all(&info)
+        .and_then(|_| fs::write(info.join("sparse-checkout"), contents))
+        .map_err(|e| Error::from_str(&format!("Failed to write sparse-checkout file: {}", e)))?;
+    repo.config()?.set_bool("core.sparseCheckout", true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{commit_file, setup_repos, test_keys};
+    use crate::{fetch_commit, pull_repo};
+
+    #[test]
+    fn test_only_matching_paths_are_materialized() {
+        let repos = setup_repos("sparse");
+        let upstream = Repository::open(&repos.upstream).unwrap();
+        fs::create_dir_all(repos.upstream.join("service")).unwrap();
+        fs::create_dir_all(repos.upstream.join("docs")).unwrap();
+        commit_file(&upstream, "service/main.txt", "service\n", "Add service");
+        let latest_sha = commit_file(&upstream, "docs/guide.txt", "docs\n", "Add docs");
+        fetch_commit(&repos.local, &mut test_keys().fetch_options(), "origin", "master", latest_sha).unwrap();
+
+        pull_repo(&repos.local, latest_sha, &["service".to_string()]).unwrap();
+
+        assert!(repos.local.join("service/main.txt").exists());
+        assert!(!repos.local.join("docs").exists());
+        assert!(!repos.local.join("README.md").exists());
+        let local = Repository::open(&repos.local).unwrap();
+        let index = local.index().unwrap();
+        let docs = index.get_path(Path::new("docs/guide.txt"), 0).unwrap();
+        assert!(IndexEntryExtendedFlag::from_bits_truncate(docs.flags_extended).is_skip_worktree());
+        assert_eq!(latest_sha, local.head().unwrap().target().unwrap());
+    }
+
+    #[test]
+    fn test_subtree_id() {
+        let repos = setup_repos("subtree-id");
+        let upstream = Repository::open(&repos.upstream).unwrap();
+        fs::create_dir_all(repos.upstream.join("service")).unwrap();
+        let first = commit_file(&upstream, "service/main.txt", "service\n", "Add service");
+        let second = commit_file(&upstream, "README.md", "readme\n", "Update readme");
+        let third = commit_file(&upstream, "service/main.txt", "changed\n", "Change service");
+        let prefix = Path::new("service");
+
+        let id = subtree_id(&upstream, first, prefix).unwrap();
+        assert!(id.is_some());
+        assert_eq!(id, subtree_id(&upstream, second, prefix).unwrap());
+        assert_ne!(id, subtree_id(&upstream, third, prefix).unwrap());
+        assert_eq!(None, subtree_id(&upstream, first, Path::new("missing")).unwrap());
+    }
+}
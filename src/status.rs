@@ -0,0 +1,148 @@
+//! `status`: a detection-only snapshot of the watched branch for dashboards.
+
+use anyhow::{Context, Result};
+use git2::{Oid, Repository, Signature};
+
+use crate::clean::modified_tracked_files;
+use crate::output::{format_timestamp, json_opt, json_string, OutputFormat};
+use crate::watcher::Watcher;
+use crate::Settings;
+
+/// Reflog messages written by the watcher all start with this.
+pub const REFLOG_PREFIX: &str = "repo-watcher:";
+
+pub struct BranchStatus {
+    pub name: String,
+    pub branch: String,
+    pub local_sha: Oid,
+    pub remote_sha: Oid,
+    pub behind: usize,
+    pub ahead: usize,
+    pub clean: bool,
+    /// When the watcher last moved the branch, in seconds since the epoch.
+    pub last_pull: Option<i64>,
+}
+
+/// Checks the remote without pulling. Missing objects for the remote tip
+/// are fetched so ahead/behind can be counted; the working tree and local
+/// branch are left alone.
+pub fn status(settings: &Settings) -> Result<BranchStatus> {
+    let watcher = Watcher::new(settings);
+    let remote_sha = watcher.detect().context("Failed to read the remote branch")?;
+
+    let repo = Repository::open(&settings.local_path)?;
+    if repo.find_commit(remote_sha).is_err() {
+        watcher.fetch_objects(remote_sha).context("Failed to fetch the remote branch")?;
+    }
+
+    let head = repo.head()?;
+    let local_sha = head.peel_to_commit()?.id();
+    let (ahead, behind) = repo.graph_ahead_behind(local_sha, remote_sha)?;
+
+    Ok(BranchStatus {
+        name: settings.name.clone(),
+        branch: settings.branch.clone(),
+        local_sha,
+        remote_sha,
+        behind,
+        ahead,
+        clean: is_clean(&repo)?,
+        last_pull: last_pull(&repo, head.name().unwrap_or("HEAD"))?,
+    })
+}
+
+/// True if no tracked file differs from HEAD. Untracked files and files
+/// left out by a sparse checkout don't count.
+pub fn is_clean(repo: &Repository) -> Result<bool, git2::Error> {
+    Ok(modified_tracked_files(repo)?.is_empty())
+}
+
+/// Moves what HEAD points at to `sha`, recording `how` in the reflog
+/// `last_pull` reads. Also used once something else has moved it, to
+/// leave that record.
+pub fn record_pull(repo: &Repository, sha: Oid, how: &str) -> Result<(), git2::Error> {
+    let message = format!("{} {}", REFLOG_PREFIX, how);
+    let mut head = repo.head()?;
+    if head.target() != Some(sha) {
+        head.set_target(sha, &message)?;
+        return Ok(());
+    }
+    // libgit2 writes no entry for a ref that does not move
+    let mut reflog = repo.reflog(head.name().unwrap_or("HEAD"))?;
+    let committer = repo.signature().or_else(|_| Signature::now("unknown", "unknown"))?;
+    reflog.append(sha, &committer, Some(&message))?;
+    reflog.write()
+}
+
+fn last_pull(repo: &Repository, refname: &str) -> Result<Option<i64>, git2::Error> {
+    let reflog = repo.reflog(refname)?;
+    let latest = reflog.iter()
+        .find(|entry| entry.message().is_some_and(|m| m.starts_with(REFLOG_PREFIX)))
+        .map(|entry| entry.committer().when().seconds());
+    Ok(latest)
+}
+
+impl BranchStatus {
+    pub fn to_human(&self) -> String {
+        format!(
+            "[{}] {}: local {} remote {}, behind {}, ahead {}, {}, last pull {}",
+            self.name,
+            self.branch,
+            self.local_sha,
+            self.remote_sha,
+            self.behind,
+            self.ahead,
+            if self.clean { "clean" } else { "dirty" },
+            self.last_pull.map(format_timestamp).unwrap_or_else(|| "never".to_string()),
+        )
+    }
+
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"name\":{},\"branch\":{},\"local_sha\":\"{}\",\"remote_sha\":\"{}\",\"behind\":{},\"ahead\":{},\"clean\":{},\"last_pull\":{}}}",
+            json_string(&self.name),
+            json_string(&self.branch),
+            self.local_sha,
+            self.remote_sha,
+            self.behind,
+            self.ahead,
+            self.clean,
+            json_opt(self.last_pull.map(format_timestamp).as_deref()),
+        )
+    }
+}
+
+/// Renders the statuses of all watched repositories; JSON is always an array.
+pub fn render(statuses: &[BranchStatus], format: OutputFormat) -> String {
+    match format {
+        OutputFormat::Human => statuses.iter().map(BranchStatus::to_human).collect::<Vec<_>>().join("\n"),
+        OutputFormat::Json => {
+            let items: Vec<String> = statuses.iter().map(BranchStatus::to_json).collect();
+            format!("[{}]", items.join(","))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{commit_file, settings_for, setup_repos};
+    use std::fs;
+
+    #[test]
+    fn test_status_does_not_pull() {
+        let repos = setup_repos("status");
+        let upstream = Repository::open(&repos.upstream).unwrap();
+        let remote_sha = commit_file(&upstream, "README.md", "second\n", "Second commit");
+        fs::write(repos.local.join("README.md"), "edited\n").unwrap();
+
+        let status = status(&settings_for(&repos)).unwrap();
+
+        assert_eq!(remote_sha, status.remote_sha);
+        assert_ne!(remote_sha, status.local_sha);
+        assert_eq!((1, 0), (status.behind, status.ahead));
+        assert!(!status.clean);
+        assert_eq!(None, status.last_pull);
+        assert!(render(&[status], OutputFormat::Json).starts_with("[{\"name\":\"test\""));
+    }
+}
@@ -0,0 +1,110 @@
+//! Coarse bandwidth limiting for fetches.
+//!
+//! libgit2 cannot throttle a transfer, so the transfer progress callback
+//! sleeps whenever the bytes received so far arrived faster than the limit
+//! allows. Packets still arrive at line rate; only the average is capped.
+
+use anyhow::{anyhow, Result};
+use git2::RemoteCallbacks;
+use std::cell::Cell;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::disk;
+
+#[derive(Clone)]
+pub struct Throttle {
+    bytes_per_sec: u64,
+    started: Cell<Option<Instant>>,
+    received: Cell<usize>,
+    elapsed: Cell<Duration>,
+}
+
+impl Throttle {
+    pub fn new(bytes_per_sec: u64) -> Throttle {
+        Throttle {
+            bytes_per_sec,
+            started: Cell::new(None),
+            received: Cell::new(0),
+            elapsed: Cell::new(Duration::ZERO),
+        }
+    }
+
+    /// Adds the rate limiting progress callback to `callbacks`.
+    pub fn install<'a>(&'a self, callbacks: &mut RemoteCallbacks<'a>) {
+        callbacks.transfer_progress(move |progress| {
+            self.pace(progress.received_bytes());
+            true
+        });
+    }
+
+    /// Sleeps until `received` bytes, the total so far, are within the limit.
+    pub fn pace(&self, received: usize) {
+        let started = self.started.get().unwrap_or_else(Instant::now);
+        self.started.set(Some(started));
+        thread::sleep(delay(received, started.elapsed(), self.bytes_per_sec));
+        self.received.set(received);
+        self.elapsed.set(started.elapsed());
+    }
+
+    /// Takes over the figures of `other`, a clone used on another thread.
+    pub fn adopt(&self, other: &Throttle) {
+        self.started.set(other.started.get());
+        self.received.set(other.received.get());
+        self.elapsed.set(other.elapsed.get());
+    }
+
+    pub fn received_bytes(&self) -> usize {
+        self.received.get()
+    }
+
+    /// The average rate of the transfer so far in bytes per second, or
+    /// `None` if nothing was transferred.
+    pub fn average_rate(&self) -> Option<f64> {
+        let secs = self.elapsed.get().as_secs_f64();
+        (self.received.get() > 0 && secs > 0.0).then(|| self.received.get() as f64 / secs)
+    }
+}
+
+/// How long to wait so that `received` bytes after `elapsed` stay within
+/// `bytes_per_sec`.
+fn delay(received: usize, elapsed: Duration, bytes_per_sec: u64) -> Duration {
+    let due = Duration::from_secs_f64(received as f64 / bytes_per_sec as f64);
+    due.saturating_sub(elapsed)
+}
+
+/// Parses a rate in bytes per second such as `512K` or `2MB/s`.
+pub fn parse_rate(s: &str) -> Result<u64> {
+    let rate = disk::parse_size(s.trim().trim_end_matches("/s"))
+        .map_err(|_| anyhow!("'{}' is not a rate such as 500K or 2M", s))?;
+    if rate == 0 {
+        return Err(anyhow!("the bandwidth limit must be above zero"));
+    }
+    Ok(rate)
+}
+
+/// Formats bytes per second for humans, e.g. `512.0 KiB/s`.
+pub fn format_rate(bytes_per_sec: f64) -> String {
+    format!("{}/s", disk::format_size(bytes_per_sec as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delay() {
+        assert_eq!(Duration::from_millis(500), delay(1024, Duration::from_millis(500), 1024));
+        assert_eq!(Duration::ZERO, delay(1024, Duration::from_secs(2), 1024));
+    }
+
+    #[test]
+    fn test_parse_rate() {
+        assert_eq!(1500, parse_rate("1500").unwrap());
+        assert_eq!(512 * 1024, parse_rate("512K").unwrap());
+        assert_eq!(2 << 20, parse_rate("2MB/s").unwrap());
+        assert!(parse_rate("0").is_err());
+        assert!(parse_rate("fast").is_err());
+        assert!(parse_rate("5T").is_err());
+    }
+}
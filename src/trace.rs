@@ -0,0 +1,66 @@
+//! `--trace-file`: a timestamped JSON lines record of every step of a
+//! run, for bug reports. Nothing is recorded unless it was opened.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::output::json_string;
+
+struct Trace {
+    file: File,
+    started: Instant,
+}
+
+static TRACE: OnceLock<Mutex<Trace>> = OnceLock::new();
+
+/// Starts appending events to `path`.
+pub fn open(path: &Path) -> io::Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    TRACE.set(Mutex::new(Trace { file, started: Instant::now() }))
+        .map_err(|_| io::Error::new(io::ErrorKind::AlreadyExists, "trace file is already open"))
+}
+
+/// Records `step` with `fields`, whose values must already be JSON.
+pub fn event(step: &str, fields: &[(&str, String)]) {
+    let Some(trace) = TRACE.get() else { return };
+    let Ok(mut trace) = trace.lock() else { return };
+
+    let started = trace.started;
+    // A trace that cannot be written must not break the run
+    write_event(&mut trace.file, started, step, fields).ok();
+}
+
+/// Writes one event line to `out`, timed against `started`.
+fn write_event(out: &mut impl Write, started: Instant, step: &str, fields: &[(&str, String)]) -> io::Result<()> {
+    let time_ms = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or(0);
+    let mut line = format!("{{\"time_ms\":{},\"elapsed_ms\":{},\"step\":{}", time_ms, started.elapsed().as_millis(), json_string(step));
+    for (key, value) in fields {
+        line.push_str(&format!(",{}:{}", json_string(key), value));
+    }
+    line.push_str("}\n");
+    out.write_all(line.as_bytes())
+}
+
+/// `s` as a JSON string field value.
+pub fn text(s: impl AsRef<str>) -> String {
+    json_string(s.as_ref())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_event() {
+        let mut out = Vec::new();
+
+        write_event(&mut out, Instant::now(), "self_check", &[("remote", text("origin")), ("count", 3.to_string())]).unwrap();
+
+        let line = String::from_utf8(out).unwrap();
+        assert!(line.starts_with("{\"time_ms\":"));
+        assert!(line.ends_with("\"step\":\"self_check\",\"remote\":\"origin\",\"count\":3}\n"));
+    }
+}
@@ -0,0 +1,228 @@
+//! The two phases of a run, fetch and apply, as separate steps.
+//!
+//! `update` chains them with its guards in between; callers that want to
+//! fetch now and apply later, after an approval or inside a window, can
+//! drive a `Watcher` directly. This is a binary crate, so "public" means
+//! visible to the rest of the crate.
+
+use anyhow::{Context, Result};
+use git2::{FetchOptions, Oid, Repository};
+use std::time::{Duration, Instant};
+
+use crate::throttle::Throttle;
+use crate::{
+    advertised_commit_sha, advertised_commit_sha_within, fetch_commit, fetch_commit_within, fetch_latest_commit_sha, head_sha, hooks,
+    pull_repo, trace, tracking_commit_sha, Cli, Settings,
+};
+
+/// Where `Watcher::detect` reads the remote tip from.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Detect {
+    /// The remote's ref advertisement; objects are fetched separately.
+    Advertisement,
+    /// `--full-fetch`: fetch the branch and read FETCH_HEAD.
+    FullFetch,
+    /// `--compare-with-remote-tracking`: the local remote-tracking ref,
+    /// without touching the network.
+    RemoteTracking,
+    /// `--detect-command`: the stdout of a command.
+    Command(String),
+}
+
+impl Detect {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Detect::Advertisement => "advertisement",
+            Detect::FullFetch => "full_fetch",
+            Detect::RemoteTracking => "remote_tracking",
+            Detect::Command(_) => "command",
+        }
+    }
+}
+
+pub struct Watcher<'a> {
+    pub settings: &'a Settings,
+    pub detect: Detect,
+    /// Limit on connecting to the remote, for the advertisement and again
+    /// for the fetch.
+    pub connect_timeout: Option<Duration>,
+    pub throttle: Option<Throttle>,
+    /// Paths to limit the checkout to in `apply`; empty for a full checkout.
+    pub sparse_paths: Vec<String>,
+}
+
+/// What `Watcher::apply` did.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Applied {
+    /// HEAD already contained the commit.
+    AlreadyContained,
+    FastForward,
+    /// The histories diverged. The merge is in the index and working tree
+    /// but not committed, as `git merge --no-commit` leaves it.
+    MergePending,
+}
+
+/// What `Watcher::fetch` found, with the objects for `remote_sha` present
+/// locally.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Detection {
+    pub local_sha: Oid,
+    pub remote_sha: Oid,
+    pub behind: usize,
+    pub ahead: usize,
+}
+
+impl<'a> Watcher<'a> {
+    pub fn new(settings: &'a Settings) -> Self {
+        Watcher { settings, detect: Detect::Advertisement, connect_timeout: None, throttle: None, sparse_paths: Vec::new() }
+    }
+
+    /// A watcher set up with the detection and fetch flags in `args`.
+    pub fn from_args(args: &Cli, settings: &'a Settings) -> Self {
+        let detect = match &args.detect_command {
+            Some(command) => Detect::Command(command.clone()),
+            None if args.compare_with_remote_tracking => Detect::RemoteTracking,
+            None if args.full_fetch => Detect::FullFetch,
+            None => Detect::Advertisement,
+        };
+        Watcher {
+            detect,
+            connect_timeout: args.connect_timeout.map(Duration::from_secs),
+            throttle: args.max_bandwidth.map(Throttle::new),
+            ..Watcher::new(settings)
+        }
+    }
+
+    fn fetch_options(&self) -> FetchOptions<'_> {
+        let mut callbacks = self.settings.ssh_keys.callbacks();
+        if let Some(throttle) = &self.throttle {
+            throttle.install(&mut callbacks);
+        }
+        let mut fetch_options = FetchOptions::new();
+        fetch_options.remote_callbacks(callbacks);
+        fetch_options
+    }
+
+    /// Reads the remote tip. Only `Detect::FullFetch` fetches objects here.
+    pub fn detect(&self) -> Result<Oid> {
+        let settings = self.settings;
+        let path = settings.local_path.as_path();
+        let started = Instant::now();
+        let sha = match &self.detect {
+            Detect::Command(command) => {
+                let envs = [
+                    ("REPO_WATCHER_REPO_NAME", settings.name.clone()),
+                    ("REPO_WATCHER_LOCAL_PATH", settings.local_path.to_string_lossy().into_owned()),
+                    ("REPO_WATCHER_REMOTE", settings.remote.clone()),
+                    ("REPO_WATCHER_BRANCH", settings.branch.clone()),
+                ];
+                hooks::detect_sha(command, &envs).context("Detect command failed")?
+            }
+            Detect::RemoteTracking => tracking_commit_sha(path, &settings.remote, &settings.branch)
+                .context("Failed to fetch the latest commit SHA")?,
+            Detect::FullFetch => {
+                let sha = fetch_latest_commit_sha(path, &mut self.fetch_options(), &settings.remote, &settings.branch)
+                    .context("Failed to fetch the latest commit SHA")?;
+                settings.ssh_keys.accept();
+                sha
+            }
+            Detect::Advertisement => match self.connect_timeout {
+                Some(timeout) => advertised_commit_sha_within(path, &settings.ssh_keys, &settings.remote, &settings.branch, timeout)
+                    .context("Failed to connect to the remote")?,
+                None => advertised_commit_sha(path, &settings.ssh_keys, &settings.remote, &settings.branch)
+                    .context("Failed to fetch the latest commit SHA")?,
+            },
+        };
+        trace::event("detect", &[
+            ("method", trace::text(self.detect.kind())),
+            ("sha", trace::text(sha.to_string())),
+            ("ms", started.elapsed().as_millis().to_string()),
+        ]);
+        Ok(sha)
+    }
+
+    /// Fetches the objects for `sha`, read by `detect`, into the
+    /// remote-tracking ref and counts how far HEAD is from it. Nothing is
+    /// fetched when `detect` already has the objects.
+    pub fn fetch_objects(&self, sha: Oid) -> Result<Detection> {
+        let settings = self.settings;
+        let path = settings.local_path.as_path();
+        match (&self.detect, self.connect_timeout) {
+            (Detect::FullFetch | Detect::RemoteTracking, _) => {}
+            (_, Some(timeout)) => fetch_commit_within(path, &settings.ssh_keys, self.throttle.as_ref(), &settings.remote, &settings.branch, sha, timeout)
+                .context("Failed to fetch new commits")?,
+            (_, None) => {
+                fetch_commit(path, &mut self.fetch_options(), &settings.remote, &settings.branch, sha)
+                    .context("Failed to fetch new commits")?;
+                settings.ssh_keys.accept();
+            }
+        }
+
+        let local_sha = head_sha(path)?;
+        let (ahead, behind) = Repository::open(path)?.graph_ahead_behind(local_sha, sha)?;
+        Ok(Detection { local_sha, remote_sha: sha, behind, ahead })
+    }
+
+    /// Detects the remote tip and fetches it. HEAD, the index and the
+    /// working tree are left alone.
+    pub fn fetch(&self) -> Result<Detection> {
+        self.fetch_objects(self.detect()?)
+    }
+
+    /// Fast-forwards HEAD to `sha`, or merges `sha` into the working tree,
+    /// which must already have been fetched.
+    pub fn apply(&self, sha: Oid) -> Result<Applied> {
+        pull_repo(&self.settings.local_path, sha, &self.sparse_paths).context("Failed to pull new commits")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{commit_file, settings_for, setup_repos};
+
+    #[test]
+    fn test_fetch_leaves_checkout_alone() {
+        let repos = setup_repos("watcher-fetch");
+        let settings = settings_for(&repos);
+        let initial = head_sha(&repos.local).unwrap();
+        let upstream = Repository::open(&repos.upstream).unwrap();
+        let latest_sha = commit_file(&upstream, "README.md", "second\n", "Second commit");
+
+        let detection = Watcher::new(&settings).fetch().unwrap();
+
+        assert_eq!(Detection { local_sha: initial, remote_sha: latest_sha, behind: 1, ahead: 0 }, detection);
+        let local = Repository::open(&repos.local).unwrap();
+        assert_eq!(latest_sha, local.refname_to_id("refs/remotes/origin/master").unwrap());
+        assert_eq!(initial, head_sha(&repos.local).unwrap());
+    }
+
+    #[test]
+    fn test_fetch_from_remote_tracking() {
+        let repos = setup_repos("watcher-tracking");
+        let settings = settings_for(&repos);
+        let initial = head_sha(&repos.local).unwrap();
+        let upstream = Repository::open(&repos.upstream).unwrap();
+        commit_file(&upstream, "README.md", "second\n", "Not fetched yet");
+        let watcher = Watcher { detect: Detect::RemoteTracking, ..Watcher::new(&settings) };
+
+        assert_eq!(Detection { local_sha: initial, remote_sha: initial, behind: 0, ahead: 0 }, watcher.fetch().unwrap());
+    }
+
+    #[test]
+    fn test_apply_fetched_sha() {
+        let repos = setup_repos("watcher-apply");
+        let settings = settings_for(&repos);
+        let upstream = Repository::open(&repos.upstream).unwrap();
+        let latest_sha = commit_file(&upstream, "README.md", "second\n", "Second commit");
+        let watcher = Watcher::new(&settings);
+
+        // Nothing fetched yet
+        assert!(watcher.apply(latest_sha).is_err());
+
+        let detection = watcher.fetch().unwrap();
+        assert_eq!(Applied::FastForward, watcher.apply(detection.remote_sha).unwrap());
+        assert_eq!(latest_sha, head_sha(&repos.local).unwrap());
+        assert_eq!(Applied::AlreadyContained, watcher.apply(latest_sha).unwrap());
+    }
+}
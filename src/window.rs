@@ -0,0 +1,131 @@
+//! Maintenance windows outside of which pulls are deferred.
+
+use anyhow::{anyhow, Error};
+use std::fmt;
+use std::str::FromStr;
+
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+/// A daily `HH:MM-HH:MM` window in local time. A window whose end is
+/// before its start wraps past midnight (e.g. `22:00-02:00`).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DeployWindow {
+    start: u32,
+    end: u32,
+}
+
+impl DeployWindow {
+    pub fn contains(&self, minute: u32) -> bool {
+        if self.start <= self.end {
+            (self.start..self.end).contains(&minute)
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+
+    fn minutes_until_open(&self, minute: u32) -> u32 {
+        (self.start + MINUTES_PER_DAY - minute) % MINUTES_PER_DAY
+    }
+}
+
+impl FromStr for DeployWindow {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s.split_once('-')
+            .ok_or_else(|| anyhow!("expected HH:MM-HH:MM, got '{}'", s))?;
+        let window = DeployWindow { start: parse_time(start)?, end: parse_time(end)? };
+        if window.start == window.end {
+            return Err(anyhow!("window '{}' is empty", s));
+        }
+        Ok(window)
+    }
+}
+
+impl fmt::Display for DeployWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", format_time(self.start), format_time(self.end))
+    }
+}
+
+fn parse_time(s: &str) -> Result<u32, Error> {
+    let (hours, minutes) = s.trim().split_once(':')
+        .ok_or_else(|| anyhow!("expected HH:MM, got '{}'", s))?;
+    let hours: u32 = hours.parse().map_err(|_| anyhow!("invalid hour in '{}'", s))?;
+    let minutes: u32 = minutes.parse().map_err(|_| anyhow!("invalid minute in '{}'", s))?;
+    if hours > 23 || minutes > 59 {
+        return Err(anyhow!("'{}' is not a valid time of day", s));
+    }
+    Ok(hours * 60 + minutes)
+}
+
+pub fn format_time(minute: u32) -> String {
+    format!("{:02}:{:02}", minute / 60, minute % 60)
+}
+
+/// Returns `None` if `minute` falls inside one of `windows` (or there are
+/// none), otherwise the start of the window that opens next.
+pub fn deferred_until(windows: &[DeployWindow], minute: u32) -> Option<u32> {
+    if windows.is_empty() || windows.iter().any(|w| w.contains(minute)) {
+        return None;
+    }
+    windows.iter()
+        .min_by_key(|w| w.minutes_until_open(minute))
+        .map(|w| w.start)
+}
+
+/// Minutes since local midnight.
+#[cfg(unix)]
+pub fn local_minute_of_day() -> u32 {
+    // SAFETY: localtime_r only writes into the tm struct we hand it
+    unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut tm: libc::tm = std::mem::zeroed();
+        libc::localtime_r(&now, &mut tm);
+        (tm.tm_hour * 60 + tm.tm_min) as u32
+    }
+}
+
+/// Minutes since midnight UTC; local time is not available here.
+#[cfg(not(unix))]
+pub fn local_minute_of_day() -> u32 {
+    let secs = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    ((secs / 60) % MINUTES_PER_DAY as u64) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_window() {
+        let window: DeployWindow = "02:00-04:30".parse().unwrap();
+
+        assert_eq!("02:00-04:30", window.to_string());
+        assert!("02:00".parse::<DeployWindow>().is_err());
+        assert!("25:00-04:00".parse::<DeployWindow>().is_err());
+        assert!("04:00-04:00".parse::<DeployWindow>().is_err());
+    }
+
+    #[test]
+    fn test_deferred_until() {
+        let windows: Vec<DeployWindow> = vec!["02:00-04:00".parse().unwrap(), "22:00-23:00".parse().unwrap()];
+
+        assert_eq!(None, deferred_until(&windows, 3 * 60));
+        assert_eq!(Some(22 * 60), deferred_until(&windows, 12 * 60));
+        assert_eq!(Some(2 * 60), deferred_until(&windows, 23 * 60 + 30));
+        assert_eq!(None, deferred_until(&[], 12 * 60));
+    }
+
+    #[test]
+    fn test_window_wraps_midnight() {
+        let window: DeployWindow = "22:00-02:00".parse().unwrap();
+
+        assert!(window.contains(23 * 60));
+        assert!(window.contains(60));
+        assert!(!window.contains(12 * 60));
+    }
+}
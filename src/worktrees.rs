@@ -0,0 +1,67 @@
+//! Safety checks for repositories that have linked worktrees.
+
+use git2::{Error, ErrorClass, ErrorCode, Repository, Worktree};
+
+/// Returns warnings about linked worktrees before HEAD is updated, or an
+/// error if one of them has the same branch checked out: moving that branch
+/// would leave the other worktree's files out of step with its HEAD.
+pub fn check(repo: &Repository) -> Result<Vec<String>, Error> {
+    let names = repo.worktrees()?;
+    if names.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let head = repo.head()?;
+    let branch = head.name().filter(|_| head.is_branch());
+    let own_path = repo.workdir().and_then(|p| p.canonicalize().ok());
+
+    let mut warnings = Vec::new();
+    for name in names.iter().flatten() {
+        let worktree = repo.find_worktree(name)?;
+        // Opened through the linked worktree itself
+        if own_path.is_some() && worktree.path().canonicalize().ok() == own_path {
+            continue;
+        }
+        let other_branch = checked_out_branch(&worktree);
+        if branch.is_some() && other_branch.as_deref() == branch {
+            return Err(Error::new(
+                ErrorCode::Locked,
+                ErrorClass::Worktree,
+                format!("{} is also checked out in worktree '{}' at {}; refusing to update it",
+                    branch.unwrap_or_default(), name, worktree.path().display()),
+            ));
+        }
+        warnings.push(format!(
+            "Linked worktree '{}' at {} ({}) is left untouched",
+            name,
+            worktree.path().display(),
+            other_branch.as_deref().unwrap_or("detached"),
+        ));
+    }
+    Ok(warnings)
+}
+
+fn checked_out_branch(worktree: &Worktree) -> Option<String> {
+    let repo = Repository::open_from_worktree(worktree).ok()?;
+    let head = repo.head().ok()?;
+    head.is_branch().then(|| head.name().map(str::to_string)).flatten()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::setup_repos;
+
+    #[test]
+    fn test_warns_about_linked_worktrees() {
+        let repos = setup_repos("worktrees");
+        let repo = Repository::open(&repos.local).unwrap();
+        assert!(check(&repo).unwrap().is_empty());
+
+        repo.worktree("preview", &repos.root.join("preview"), None).unwrap();
+
+        let warnings = check(&repo).unwrap();
+        assert_eq!(1, warnings.len());
+        assert!(warnings[0].contains("'preview'"));
+    }
+}